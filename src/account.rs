@@ -1,176 +1,723 @@
 use crate::transaction::Transaction;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
-#[derive(Debug, Clone, Eq)]
+// A client has an account per currency. The currency is None for the default one, the only
+// one of an input without currency column
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AccountKey {
+    pub client_id: u16,
+    pub currency: Option<String>,
+}
+
+impl AccountKey {
+    pub fn build(client_id: u16, currency: Option<String>) -> Self {
+        Self {
+            client_id,
+            currency,
+        }
+    }
+}
+
+// The account of the client in the default currency
+impl From<u16> for AccountKey {
+    fn from(client_id: u16) -> Self {
+        Self::build(client_id, None)
+    }
+}
+
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct Account {
     pub client_id: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
     pub held_funds: u64,
-    pub available_funds: u64,
+    // signed because an overdraft policy can leave it negative
+    pub available_funds: i64,
     pub locked: bool,
-    // tx: info
-    pub transaction_history: HashMap<u32, Transaction>,
+    // number of decimal places the funds are stored with
+    pub decimals: u32,
+    // how the amounts with more decimals are scaled to them
+    #[serde(default)]
+    pub rounding: Rounding,
+    // tx: info, sorted by tx so the history is always reported and printed in the same order.
+    // The O(log n) insertions and lookups are not noticeable next to the parsing of the input
+    pub transaction_history: BTreeMap<u32, Transaction>,
+    // reference: tx of the transactions created with a reference (see Operation::reference)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub references: BTreeMap<String, u32>,
+    // units withdrawn during this run, for the withdrawal cap of the policy. Not saved in the
+    // snapshots, the cap is per run
+    #[serde(skip)]
+    pub withdrawn: u64,
 }
 
-// Implementation of PartialEq for testing purposes, I am omitting the comparison of
-// `transaction history` because I do not have time to do such verbose tests
+// Implementation of PartialEq for testing purposes. The transaction history and the references
+// are compared as well, only `withdrawn` is left out since it is not part of the saved state
 impl PartialEq for Account {
     fn eq(&self, other: &Self) -> bool {
         self.client_id == other.client_id
+            && self.currency == other.currency
             && self.held_funds == other.held_funds
             && self.available_funds == other.available_funds
             && self.locked == other.locked
+            && self.decimals == other.decimals
+            && self.rounding == other.rounding
             && self.transaction_history == other.transaction_history
+            && self.references == other.references
+    }
+}
+// Reasons why the funds of an account cannot be modified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FundsError {
+    // the result would be bigger than the maximum value an account can hold
+    Overflow,
+    // there are not enough funds to substract the amount
+    InsufficientFunds,
+    // negative (or not finite) amount
+    InvalidAmount,
+}
+
+impl fmt::Display for FundsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "the funds would exceed the maximum value"),
+            Self::InsufficientFunds => write!(f, "insufficient funds"),
+            Self::InvalidAmount => write!(f, "invalid amount"),
+        }
     }
 }
-/// IMPORTANT NOTE: these function return bool instead of a custom error message/type
-/// just because lack of time. Ideally they will return a proper error type.
+
+impl std::error::Error for FundsError {}
+
+// How an amount is scaled to the stored units when it has more decimals than the account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Rounding {
+    // to the nearest unit, the ties away from zero
+    #[default]
+    Nearest,
+    // to the nearest unit, the ties to the even one
+    Bankers,
+    // the extra decimals are dropped
+    Truncate,
+}
+
+impl Rounding {
+    // The scaled amount carries the binary error of the f64 (e.g. 0.00005 * 10^4 is not
+    // exactly 0.5), so values within a few ulps of a unit or of a tie are taken as such
+    fn apply(&self, units: f64) -> f64 {
+        let tolerance = (units.abs() * 8.0 * f64::EPSILON).max(f64::EPSILON);
+        let nearest = units.round();
+        if (units - nearest).abs() <= tolerance {
+            return nearest;
+        }
+        // the amounts are never negative, so away from zero is up
+        let floor = units.floor();
+        let tie = (units - floor - 0.5).abs() <= tolerance;
+        match self {
+            Self::Nearest if tie => floor + 1.0,
+            Self::Bankers if tie && floor % 2.0 == 0.0 => floor,
+            Self::Bankers if tie => floor + 1.0,
+            Self::Nearest | Self::Bankers => nearest,
+            Self::Truncate => floor,
+        }
+    }
+}
+
 impl Account {
-    /// I must cap the precision to 0.0001, because if we do f64.to_bits()
+    /// I must cap the precision (by default to 0.0001), because if we do f64.to_bits()
     /// it will be transmuted with all the decimal part, reducing considerably
     /// the maximum number which could use
-    const PRECISION: f64 = 0.0001;
-    /// The maximum which can have is 15 digits
-    const MAX_VALUE: u64 = 1_000_000_000_000_000;
+    pub const DEFAULT_DECIMALS: u32 = 4;
+    /// Maximum number of units both funds can have, the limit of the signed available funds.
+    /// The maximum amount depends on the decimals: 922337203685477.5807 with the default 4.
+    /// The stored sums are exact up to it, though amounts of more than 2^53 units
+    /// (900719925474.0992 with 4 decimals) are not exactly representable in the f64 input
+    pub const MAX_VALUE: u64 = i64::MAX as u64;
 
     pub fn build(client_id: u16) -> Self {
+        Self::build_with_decimals(client_id, Self::DEFAULT_DECIMALS)
+    }
+
+    pub fn build_with_decimals(client_id: u16, decimals: u32) -> Self {
         Self {
             client_id,
+            currency: None,
             held_funds: 0,
             available_funds: 0,
             locked: false,
+            decimals,
+            rounding: Default::default(),
             transaction_history: Default::default(),
+            references: Default::default(),
+            withdrawn: 0,
         }
     }
 
-    pub fn add(dest: &mut u64, amount: f64) -> bool {
+    // New account of the client in the currency of the key
+    pub fn build_empty(key: &AccountKey, decimals: u32, rounding: Rounding) -> Self {
+        Self::build_with_decimals(key.client_id, decimals)
+            .with_rounding(rounding)
+            .with_currency(key.currency.clone())
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_currency(mut self, currency: Option<String>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    // Factor to convert an amount into the stored fixed-point units
+    fn factor(&self) -> f64 {
+        10f64.powi(self.decimals as i32)
+    }
+
+    // Amount converted into the stored fixed-point units. None if it is negative, not finite or
+    // beyond MAX_VALUE, so the units always fit in both funds
+    pub(crate) fn scale(&self, amount: f64) -> Option<u64> {
+        if !amount.is_finite() || amount < 0.0 {
+            return None;
+        }
         // it must be rounded because f64 representation of 5.0002 would be 5.000099999
         // and it would induce a precision error
-        let transmuted_amount = (amount / Self::PRECISION).round() as u64;
-        // sanity check
-        if transmuted_amount + *dest > Self::MAX_VALUE
-            || transmuted_amount > Self::MAX_VALUE
-            || amount < 0.0
-        {
-            false
-        } else {
-            *dest += transmuted_amount;
-            true
+        let units = self.rounding.apply(amount * self.factor());
+        // MAX_VALUE as f64 is 2^63, the biggest f64 below it is already within the limit. The
+        // cast would saturate instead of failing
+        if units >= Self::MAX_VALUE as f64 {
+            return None;
         }
+        Some(units as u64)
     }
 
-    fn substract(dest: &mut u64, amount: f64) -> bool {
-        // it must be rounded because f64 representation of 5.0002 would be 5.000099999
-        // and it would induce a precision error
-        let transmuted_amount = (amount / Self::PRECISION).round() as u64;
-        // sanity check
-        if *dest < transmuted_amount || amount < 0.0 {
-            false
-        } else {
-            *dest -= transmuted_amount;
-            true
+    // Same as scale, with the reason why the amount cannot be scaled
+    fn to_units(&self, amount: f64) -> Result<u64, FundsError> {
+        match self.scale(amount) {
+            Some(units) => Ok(units),
+            None if amount.is_finite() && amount >= 0.0 => Err(FundsError::Overflow),
+            None => Err(FundsError::InvalidAmount),
         }
     }
 
-    fn add_held_funds(&mut self, amount: f64) -> bool {
-        Self::add(&mut self.held_funds, amount)
+    fn add(dest: &mut u64, units: u64) -> Result<(), FundsError> {
+        match dest.checked_add(units) {
+            Some(result) if result <= Self::MAX_VALUE => {
+                *dest = result;
+                Ok(())
+            }
+            _ => Err(FundsError::Overflow),
+        }
     }
 
-    fn substract_held_funds(&mut self, amount: f64) -> bool {
-        Self::substract(&mut self.held_funds, amount)
+    fn substract(dest: &mut u64, units: u64) -> Result<(), FundsError> {
+        if *dest < units {
+            Err(FundsError::InsufficientFunds)
+        } else {
+            *dest -= units;
+            Ok(())
+        }
     }
 
-    pub fn block_funds(&mut self, amount: f64) -> bool {
-        self.substract_funds(amount) && self.add_held_funds(amount)
+    // Also the funds of a deposit which arrives already disputed
+    pub fn add_held_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        Self::add(&mut self.held_funds, units)
     }
 
-    pub fn unblock_funds(&mut self, amount: f64) -> bool {
-        if self.substract_held_funds(amount) {
-           if self.add_funds(amount) {
-               true
-           } else {
-               self.add_held_funds(amount);
-               false
-           }
-        } else {
-            false
+    fn substract_held_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        Self::substract(&mut self.held_funds, units)
+    }
+
+    // The funds are moved from available to held, or nothing is modified at all
+    pub fn block_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        self.substract_funds(amount)?;
+        if let Err(error) = self.add_held_funds(amount) {
+            self.add_funds(amount)
+                .expect("restoring the funds just substracted cannot fail");
+            return Err(error);
         }
+        Ok(())
+    }
+
+    // Like block_funds, but the whole amount is held even if part of it was already spent (e.g.
+    // withdrawn after the deposit): the available funds go negative by what the client owes
+    // and the total does not change
+    pub fn hold_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        let available = self
+            .available_funds
+            .checked_sub(units as i64)
+            .filter(|available| *available >= -(Self::MAX_VALUE as i64))
+            .ok_or(FundsError::Overflow)?;
+        Self::add(&mut self.held_funds, units)?;
+        self.available_funds = available;
+        Ok(())
     }
 
-    pub fn retire_blocked_funds(&mut self, amount: f64) -> bool {
+    // The funds are moved from held to available, or nothing is modified at all: both results
+    // are computed before any of them is stored, so there is nothing to roll back
+    pub fn unblock_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        let held = self
+            .held_funds
+            .checked_sub(units)
+            .ok_or(FundsError::InsufficientFunds)?;
+        let available = self
+            .available_funds
+            .checked_add(units as i64)
+            .filter(|available| *available <= Self::MAX_VALUE as i64)
+            .ok_or(FundsError::Overflow)?;
+        self.held_funds = held;
+        self.available_funds = available;
+        Ok(())
+    }
+
+    // Whether the withdrawals of this run stay within the cap with the amount. An amount which
+    // cannot be scaled is left to the withdrawal to reject
+    pub fn within_withdrawal_cap(&self, amount: f64, cap: f64) -> bool {
+        let cap = self.scale(cap).unwrap_or(Self::MAX_VALUE);
+        self.scale(amount)
+            .is_none_or(|units| self.withdrawn.saturating_add(units) <= cap)
+    }
+
+    // Count an applied withdrawal towards the cap
+    pub fn record_withdrawal(&mut self, amount: f64) {
+        let units = self.scale(amount).unwrap_or_default();
+        self.withdrawn = self.withdrawn.saturating_add(units);
+    }
+
+    pub fn retire_blocked_funds(&mut self, amount: f64) -> Result<(), FundsError> {
         self.substract_held_funds(amount)
     }
 
-    pub fn add_funds(&mut self, amount: f64) -> bool {
-        Self::add(&mut self.available_funds, amount)
+    // The units are within MAX_VALUE, so they fit in the signed available funds
+    pub fn add_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        match self.available_funds.checked_add(units as i64) {
+            Some(result) if result <= Self::MAX_VALUE as i64 => {
+                self.available_funds = result;
+                Ok(())
+            }
+            _ => Err(FundsError::Overflow),
+        }
+    }
+
+    pub fn substract_funds(&mut self, amount: f64) -> Result<(), FundsError> {
+        self.overdraw_funds(amount, 0.0)
     }
 
-    pub fn substract_funds(&mut self, amount: f64) -> bool {
-        Self::substract(&mut self.available_funds, amount)
+    // Like substract_funds, but the available funds can go negative down to -limit
+    pub fn overdraw_funds(&mut self, amount: f64, limit: f64) -> Result<(), FundsError> {
+        let units = self.to_units(amount)?;
+        // a limit beyond MAX_VALUE is the same as MAX_VALUE
+        let limit = self.scale(limit).unwrap_or(Self::MAX_VALUE) as i64;
+        match self.available_funds.checked_sub(units as i64) {
+            Some(result) if result >= -limit => {
+                self.available_funds = result;
+                Ok(())
+            }
+            _ => Err(FundsError::InsufficientFunds),
+        }
     }
 
     pub fn available_funds(&self) -> String {
-        format!("{:.04}", self.available_funds as f64 * Self::PRECISION)
+        self.format(self.available_funds as i128)
     }
 
     pub fn held_funds(&self) -> String {
-        format!("{:.04}", self.held_funds as f64 * Self::PRECISION)
+        self.format(self.held_funds as i128)
+    }
+
+    // Both funds are within the limits and the held funds are exactly the amounts of the
+    // transactions currently under dispute
+    pub fn check_invariants(&self) -> bool {
+        // scaled the same way they were held
+        let disputed: u64 = self
+            .transaction_history
+            .values()
+            .filter(|transaction| transaction.disputed())
+            .map(|transaction| self.held_by(transaction))
+            .sum();
+        self.held_funds <= Self::MAX_VALUE
+            && self.available_funds.unsigned_abs() <= Self::MAX_VALUE
+            && self.held_funds == disputed
+    }
+
+    // Units a disputed transaction still holds, each part scaled the same way it was held or
+    // released
+    pub fn held_by(&self, transaction: &Transaction) -> u64 {
+        let settled: u64 = [
+            transaction.settled.resolved,
+            transaction.settled.charged_back,
+        ]
+        .iter()
+        .filter_map(|amount| self.scale(*amount))
+        .sum();
+        self.scale(transaction.amount)
+            .unwrap_or_default()
+            .saturating_sub(settled)
+    }
+
+    // Whether the amount is no more than what a disputed transaction still holds
+    pub fn covers(&self, transaction: &Transaction, amount: f64) -> bool {
+        self.scale(amount)
+            .is_some_and(|units| units <= self.held_by(transaction))
+    }
+
+    // Only checked in debug builds, it goes through the whole transaction history
+    pub fn debug_assert_invariants(&self) {
+        debug_assert!(
+            self.check_invariants(),
+            "inconsistent funds in account {:?}",
+            self
+        );
+    }
+
+    // The sum is done over the stored units, so it is exact
+    pub fn total(&self) -> String {
+        self.format(self.available_funds as i128 + self.held_funds as i128)
+    }
+
+    // Biggest amount the funds can reach with the decimals of the account
+    pub fn max_funds(&self) -> String {
+        self.format(Self::MAX_VALUE as i128)
+    }
+
+    fn format(&self, units: i128) -> String {
+        Self::format_units(units, self.decimals)
+    }
+
+    // Formatted from the integer units, as f64 the big amounts would lose their last digits
+    pub fn format_units(units: i128, decimals: u32) -> String {
+        let scale = 10u128.pow(decimals);
+        let sign = if units < 0 { "-" } else { "" };
+        let integer = units.unsigned_abs() / scale;
+        if decimals == 0 {
+            return format!("{}{}", sign, integer);
+        }
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            integer,
+            units.unsigned_abs() % scale,
+            width = decimals as usize
+        )
+    }
+
+    // Formatted amount without the trailing zeros of its decimals, nor the point if they are all
+    // zeros: 10.5000 is 10.5 and 10.0000 is 10
+    pub fn trim_zeros(amount: &str) -> &str {
+        if !amount.contains('.') {
+            return amount;
+        }
+        amount.trim_end_matches('0').trim_end_matches('.')
     }
 
     pub fn locked(&self) -> bool {
         self.locked
     }
 
+    // Locking an already locked account does nothing
     pub fn lock(&mut self) {
         self.locked = true;
     }
 
+    // A reference identifies the first transaction created with it, a later one with the same
+    // reference can only be targeted by its tx
+    pub fn index_reference(&mut self, reference: String, tx: u32) {
+        self.references.entry(reference).or_insert(tx);
+    }
+
+    // Unlocking an account which is not locked does nothing
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
     pub fn client_id(&self) -> u16 {
         self.client_id
     }
+
+    pub fn currency(&self) -> Option<&str> {
+        self.currency.as_deref()
+    }
+
+    pub fn key(&self) -> AccountKey {
+        AccountKey::build(self.client_id, self.currency.clone())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::account::Account;
+    use crate::account::{Account, FundsError, Rounding};
 
     #[test]
     fn test_available_funds() {
         let mut account = Account::build(0);
         // Adding
         assert_eq!(account.available_funds(), "0.0000");
-        assert!(account.add_funds(10.0));
+        assert!(account.add_funds(10.0).is_ok());
         assert_eq!(account.available_funds(), "10.0000");
-        assert!(!account.add_funds(-10.0));
-        assert!(account.add_funds(1000.0));
+        assert!(account.add_funds(-10.0).is_err());
+        assert!(account.add_funds(1000.0).is_ok());
         assert_eq!(account.available_funds(), "1010.0000");
-        assert!(!account.add_funds(Account::MAX_VALUE as f64 * Account::PRECISION));
+        assert!(account
+            .add_funds(Account::MAX_VALUE as f64 / account.factor())
+            .is_err());
         assert_eq!(account.available_funds(), "1010.0000");
-        assert!(account.add_funds(234924.4343));
+        assert!(account.add_funds(234924.4343).is_ok());
         assert_eq!(account.available_funds(), "235934.4343");
-        assert!(account.add_funds(Account::PRECISION));
+        assert!(account.add_funds(1.0 / account.factor()).is_ok());
         assert_eq!(account.available_funds(), "235934.4344");
         // Subtracting
-        assert!(!account.substract_funds(Account::MAX_VALUE as f64 * Account::PRECISION));
+        assert!(account
+            .substract_funds(Account::MAX_VALUE as f64 / account.factor())
+            .is_err());
         assert_eq!(account.available_funds(), "235934.4344");
-        assert!(account.substract_funds(234924.4343));
+        assert!(account.substract_funds(234924.4343).is_ok());
         assert_eq!(account.available_funds(), "1010.0001");
-        assert!(!account.substract_funds(234924.4343));
+        assert!(account.substract_funds(234924.4343).is_err());
         assert_eq!(account.available_funds(), "1010.0001");
-        assert!(account.substract_funds(1010.0000));
+        assert!(account.substract_funds(1010.0000).is_ok());
         assert_eq!(account.available_funds(), "0.0001");
-        assert!(account.substract_funds(0.0001));
+        assert!(account.substract_funds(0.0001).is_ok());
         assert_eq!(account.available_funds(), "0.0000");
     }
 
     #[test]
     fn test_held_funds() {
         let mut account = Account::build(0);
-        assert!(!account.block_funds(100.0));
+        assert!(account.block_funds(100.0).is_err());
         assert_eq!(account.available_funds(), "0.0000");
-        assert!(account.add_funds(100.0));
+        assert!(account.add_funds(100.0).is_ok());
         assert_eq!(account.available_funds(), "100.0000");
-        assert!(account.block_funds(99.0001));
+        assert!(account.block_funds(99.0001).is_ok());
         assert_eq!(account.available_funds(), "0.9999");
         assert_eq!(account.held_funds(), "99.0001");
     }
+
+    #[test]
+    fn test_hold_funds() {
+        let mut account = Account::build(0);
+        assert!(account.add_funds(100.0).is_ok());
+        assert!(account.substract_funds(60.0).is_ok());
+        assert!(account.block_funds(100.0).is_err());
+        assert!(account.hold_funds(100.0).is_ok());
+        assert_eq!(account.available_funds(), "-60.0000");
+        assert_eq!(account.held_funds(), "100.0000");
+        assert_eq!(account.total(), "40.0000");
+        // nothing is modified if it does not fit
+        assert_eq!(
+            account.hold_funds(Account::MAX_VALUE as f64 / 10_000.0),
+            Err(FundsError::Overflow)
+        );
+        assert_eq!(account.available_funds(), "-60.0000");
+        assert_eq!(account.held_funds(), "100.0000");
+    }
+
+    #[test]
+    fn test_unblock_funds() {
+        let mut account = Account::build(0);
+        assert!(account.add_funds(10.0).is_ok());
+        assert!(account.block_funds(4.0).is_ok());
+        assert_eq!(
+            account.unblock_funds(5.0),
+            Err(FundsError::InsufficientFunds)
+        );
+        assert!(account.unblock_funds(1.5).is_ok());
+        assert_eq!(account.available_funds(), "7.5000");
+        assert_eq!(account.held_funds(), "2.5000");
+
+        // the available funds are already at the maximum, so the credit overflows: the held
+        // funds are not lost
+        account.available_funds = Account::MAX_VALUE as i64;
+        assert_eq!(account.unblock_funds(2.5), Err(FundsError::Overflow));
+        assert_eq!(account.held_funds(), "2.5000");
+        assert_eq!(account.available_funds(), account.max_funds());
+        assert_eq!(account.unblock_funds(-1.0), Err(FundsError::InvalidAmount));
+        assert_eq!(account.held_funds(), "2.5000");
+    }
+
+    #[test]
+    fn test_total() {
+        let mut account = Account::build(0);
+        assert!(account.add_funds(0.3).is_ok());
+        assert!(account.block_funds(0.2).is_ok());
+        assert_eq!(account.available_funds(), "0.1000");
+        assert_eq!(account.held_funds(), "0.2000");
+        assert_eq!(account.total(), "0.3000");
+        // adding them as f64 would drift
+        assert_ne!(0.1 + 0.2, 0.3);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut account = Account::build(0);
+        assert_eq!(account.max_funds(), "922337203685477.5807");
+        assert!(account.add_funds(900_000_000_000_000.0).is_ok());
+        assert_eq!(
+            account.add_funds(30_000_000_000_000.0),
+            Err(FundsError::Overflow)
+        );
+        assert_eq!(account.available_funds(), "900000000000000.0000");
+        // absurd amounts do not wrap around
+        assert_eq!(account.add_funds(f64::MAX), Err(FundsError::Overflow));
+        assert_eq!(
+            account.add_funds(f64::INFINITY),
+            Err(FundsError::InvalidAmount)
+        );
+        assert_eq!(account.available_funds(), "900000000000000.0000");
+    }
+
+    #[test]
+    fn test_scale() {
+        let account = Account::build(0);
+        assert_eq!(account.scale(1.5), Some(15_000));
+        assert_eq!(account.scale(5.0002), Some(50_002));
+        assert_eq!(account.scale(0.0), Some(0));
+        assert_eq!(account.scale(-0.0), Some(0));
+        assert_eq!(account.scale(-0.0001), None);
+        assert_eq!(account.scale(f64::NAN), None);
+        assert_eq!(account.scale(f64::INFINITY), None);
+        assert_eq!(account.scale(f64::NEG_INFINITY), None);
+        assert_eq!(account.scale(1e16), None);
+        assert_eq!(account.scale(f64::MAX), None);
+        // the boundary: the biggest f64 below 2^63 units is within MAX_VALUE, 2^63 is not
+        let account = Account::build_with_decimals(0, 0);
+        let below = 2f64.powi(63) - 1024.0;
+        assert_eq!(account.scale(below), Some(Account::MAX_VALUE - 1023));
+        assert_eq!(account.scale(2f64.powi(63)), None);
+        assert_eq!(account.scale(Account::MAX_VALUE as f64), None);
+    }
+
+    #[test]
+    fn test_to_units() {
+        let account = Account::build(0);
+        assert_eq!(account.to_units(1e30), Err(FundsError::Overflow));
+        assert_eq!(account.to_units(f64::MAX), Err(FundsError::Overflow));
+        assert_eq!(account.to_units(-1.0), Err(FundsError::InvalidAmount));
+        assert_eq!(account.to_units(f64::NAN), Err(FundsError::InvalidAmount));
+        assert_eq!(account.to_units(2.5), Ok(25_000));
+        // the accounts reject the amounts beyond MAX_VALUE without wrapping around
+        let mut account = Account::build_with_decimals(0, 0);
+        let below = 2f64.powi(64) - 2048.0;
+        assert_eq!(account.add_funds(below), Err(FundsError::Overflow));
+        assert_eq!(account.add_held_funds(below), Err(FundsError::Overflow));
+        assert_eq!(account.add_funds(1e30), Err(FundsError::Overflow));
+        assert_eq!(account.available_funds(), "0");
+        assert_eq!(account.held_funds(), "0");
+    }
+
+    #[test]
+    fn test_rounding() {
+        let scaled = |amount: f64, rounding| {
+            let mut account = Account::build(0).with_rounding(rounding);
+            assert!(account.add_funds(amount).is_ok());
+            account.available_funds()
+        };
+        for (amount, nearest, bankers, truncate) in [
+            (0.00005, "0.0001", "0.0000", "0.0000"),
+            (0.00015, "0.0002", "0.0002", "0.0001"),
+            (0.00025, "0.0003", "0.0002", "0.0002"),
+            (1.00005, "1.0001", "1.0000", "1.0000"),
+            (0.00004999, "0.0000", "0.0000", "0.0000"),
+            (0.00005001, "0.0001", "0.0001", "0.0000"),
+            (0.00009, "0.0001", "0.0001", "0.0000"),
+            // exact amounts are never modified, even if their f64 is a bit below them
+            (5.0002, "5.0002", "5.0002", "5.0002"),
+            (0.0003, "0.0003", "0.0003", "0.0003"),
+        ] {
+            assert_eq!(scaled(amount, Rounding::Nearest), nearest, "{}", amount);
+            assert_eq!(scaled(amount, Rounding::Bankers), bankers, "{}", amount);
+            assert_eq!(scaled(amount, Rounding::Truncate), truncate, "{}", amount);
+        }
+        // the held funds are scaled the same way
+        let mut account = Account::build(0).with_rounding(Rounding::Truncate);
+        assert!(account.add_funds(1.0).is_ok());
+        assert!(account.block_funds(0.00019).is_ok());
+        assert_eq!(account.held_funds(), "0.0001");
+        assert_eq!(account.available_funds(), "0.9999");
+    }
+
+    #[test]
+    fn test_max_value() {
+        // without decimals the units are the amounts, so the boundary can be reached exactly
+        let mut account = Account::build_with_decimals(0, 0);
+        assert_eq!(account.max_funds(), "9223372036854775807");
+        assert!(account.add_funds(2f64.powi(62)).is_ok());
+        assert!(account.add_funds(2f64.powi(62) - 1024.0).is_ok());
+        assert!(account.add_funds(1023.0).is_ok());
+        assert_eq!(account.available_funds(), account.max_funds());
+        assert_eq!(account.add_funds(1.0), Err(FundsError::Overflow));
+        assert_eq!(account.available_funds(), account.max_funds());
+        // the total of both funds goes beyond the limit of each one
+        assert!(account.block_funds(1.0).is_ok());
+        assert!(account.add_funds(1.0).is_ok());
+        assert_eq!(account.total(), "9223372036854775808");
+        // the available funds cannot go below -MAX_VALUE either
+        let mut account = Account::build_with_decimals(0, 0);
+        assert!(account
+            .overdraw_funds(2f64.powi(62), Account::MAX_VALUE as f64)
+            .is_ok());
+        assert_eq!(account.available_funds(), "-4611686018427387904");
+        assert_eq!(account.hold_funds(2f64.powi(62)), Err(FundsError::Overflow));
+    }
+
+    #[test]
+    fn test_decimals() {
+        let mut two_decimals = Account::build_with_decimals(0, 2);
+        let mut four_decimals = Account::build_with_decimals(0, 4);
+        for account in [&mut two_decimals, &mut four_decimals] {
+            assert!(account.add_funds(10.1234).is_ok());
+            assert!(account.block_funds(0.0051).is_ok());
+        }
+        assert_eq!(two_decimals.available_funds(), "10.11");
+        assert_eq!(two_decimals.held_funds(), "0.01");
+        assert_eq!(four_decimals.available_funds(), "10.1183");
+        assert_eq!(four_decimals.held_funds(), "0.0051");
+
+        // the maximum value is in stored units, so fewer decimals allow bigger amounts
+        assert!(two_decimals.add_funds(10_000_000_000_000_000.0).is_ok());
+        assert!(four_decimals.add_funds(10_000_000_000_000_000.0).is_err());
+    }
+
+    #[test]
+    fn test_trim_zeros() {
+        assert_eq!(Account::trim_zeros("10.0000"), "10");
+        assert_eq!(Account::trim_zeros("10.5000"), "10.5");
+        assert_eq!(Account::trim_zeros("0.0001"), "0.0001");
+        assert_eq!(Account::trim_zeros("-2.5000"), "-2.5");
+        // without decimals the zeros are part of the integer
+        assert_eq!(Account::trim_zeros("100"), "100");
+    }
+
+    #[test]
+    fn test_overdraw() {
+        let mut account = Account::build(0);
+        assert!(account.add_funds(10.0).is_ok());
+        // within the limit
+        assert!(account.overdraw_funds(15.0, 10.0).is_ok());
+        assert_eq!(account.available_funds(), "-5.0000");
+        assert_eq!(account.total(), "-5.0000");
+        // beyond the limit nothing is modified
+        assert_eq!(
+            account.overdraw_funds(5.0001, 10.0),
+            Err(FundsError::InsufficientFunds)
+        );
+        assert_eq!(
+            account.substract_funds(1.0),
+            Err(FundsError::InsufficientFunds)
+        );
+        assert_eq!(account.available_funds(), "-5.0000");
+        assert!(account.overdraw_funds(5.0, 10.0).is_ok());
+        assert_eq!(account.available_funds(), "-10.0000");
+        // the debt is paid first
+        assert!(account.add_funds(12.5).is_ok());
+        assert_eq!(account.available_funds(), "2.5000");
+        assert!(account.check_invariants());
+    }
 }
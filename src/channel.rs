@@ -0,0 +1,77 @@
+use anyhow::Result;
+use std::fmt::Debug;
+use tokio::sync::mpsc;
+
+// Thin wrapper over tokio's mpsc channels so the tasks do not need to know whether
+// they are talking through a bounded (backpressure) or an unbounded channel
+#[derive(Debug)]
+pub enum Sender<T> {
+    Bounded(mpsc::Sender<T>),
+    Unbounded(mpsc::UnboundedSender<T>),
+}
+
+// Implemented by hand because deriving it would require T: Clone
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Bounded(sender) => Self::Bounded(sender.clone()),
+            Self::Unbounded(sender) => Self::Unbounded(sender.clone()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Receiver<T> {
+    Bounded(mpsc::Receiver<T>),
+    Unbounded(mpsc::UnboundedReceiver<T>),
+}
+
+// Create a bounded channel if a capacity is given, otherwise an unbounded one
+pub fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    match capacity {
+        Some(capacity) => {
+            let (sender, receiver) = mpsc::channel(capacity);
+            (Sender::Bounded(sender), Receiver::Bounded(receiver))
+        }
+        None => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            (Sender::Unbounded(sender), Receiver::Unbounded(receiver))
+        }
+    }
+}
+
+impl<T: Debug + Send + Sync + 'static> Sender<T> {
+    // With a bounded channel it waits until there is room, so the producer is throttled
+    pub async fn send(&self, value: T) -> Result<()> {
+        match self {
+            Self::Bounded(sender) => sender.send(value).await?,
+            Self::Unbounded(sender) => sender.send(value)?,
+        }
+        Ok(())
+    }
+
+    // Whether the receiver is gone, so nothing can be sent anymore
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Self::Bounded(sender) => sender.is_closed(),
+            Self::Unbounded(sender) => sender.is_closed(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        match self {
+            Self::Bounded(receiver) => receiver.recv().await,
+            Self::Unbounded(receiver) => receiver.recv().await,
+        }
+    }
+
+    // Next value if there is one already queued, it never waits
+    pub fn try_recv(&mut self) -> Option<T> {
+        match self {
+            Self::Bounded(receiver) => receiver.try_recv().ok(),
+            Self::Unbounded(receiver) => receiver.try_recv().ok(),
+        }
+    }
+}
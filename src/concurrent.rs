@@ -0,0 +1,630 @@
+use crate::account::{Account, AccountKey, Rounding};
+use crate::channel;
+use crate::channel::{Receiver, Sender};
+use crate::engine::Engine;
+use crate::parser::{Operation, TypeOperation};
+use crate::processor::{
+    report, shutdown_requested, OperationStatus, Policy, ProcessorOptions, Recorder,
+};
+use crate::progress::Progress;
+use crate::rejects::Rejects;
+use crate::replay::Replay;
+use crate::snapshot;
+use crate::summary::Summary;
+use crate::{reader, writer};
+use anyhow::Result;
+use dashmap::{DashMap, DashSet};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+// Processor which applies the operations in several tasks over accounts shared in a concurrent
+// map. The operations of a client always go to the same task (`client_id % tasks`), so they are
+// applied in order, but unlike the sharded processor any task can reach any account: the
+// transfers between clients of different tasks are applied. Such a transfer reads the state of
+// another task, so the router waits for all the tasks to go through their queues before sending
+// it, and for the transfer to be applied before sending anything else: the results are the same
+// as the serial ones, at the cost of stopping every task for each of those transfers
+pub struct ConcurrentProcessor {
+    receiver: Receiver<reader::Command>,
+    sender: Sender<writer::Command>,
+    tasks: usize,
+    decimals: u32,
+    rounding: Rounding,
+    policy: Policy,
+    channel_capacity: Option<usize>,
+    accounts: Arc<DashMap<AccountKey, Account>>,
+    // tx ids of all the clients, shared by the tasks
+    transactions: Arc<DashSet<u32>>,
+    // tx: client of the first operation which created it. The tasks would take the tx ids in
+    // no particular order, so a tx id reused by another client is checked here, the same way
+    // as the sharded processor does
+    owners: HashMap<u32, u16>,
+    // every task records its operations with a fork of it, their summaries are merged here
+    recorder: Recorder,
+    snapshot: Option<PathBuf>,
+    // the tasks stop with the queued operations left behind
+    shutdown: Option<watch::Receiver<bool>>,
+}
+
+impl ConcurrentProcessor {
+    pub fn build(
+        receiver: Receiver<reader::Command>,
+        sender: Sender<writer::Command>,
+        tasks: usize,
+    ) -> Self {
+        Self {
+            receiver,
+            sender,
+            tasks: tasks.max(1),
+            decimals: Account::DEFAULT_DECIMALS,
+            rounding: Default::default(),
+            policy: Default::default(),
+            channel_capacity: None,
+            accounts: Default::default(),
+            transactions: Default::default(),
+            owners: Default::default(),
+            recorder: Default::default(),
+            snapshot: None,
+            shutdown: None,
+        }
+    }
+
+    // The history options are not supported, see ProcessorOptions
+    pub fn with_options(self, options: ProcessorOptions) -> Self {
+        let mut processor = self
+            .with_decimals(options.decimals)
+            .with_rounding(options.rounding)
+            .with_policy(options.policy)
+            .with_channel_capacity(options.channel_capacity)
+            .with_database(options.database)
+            .with_snapshot(options.snapshot);
+        processor.recorder = Recorder::build(options.progress, options.rejects, options.replay);
+        if let Some(shutdown) = options.shutdown {
+            processor = processor.with_shutdown(shutdown);
+        }
+        processor
+    }
+
+    pub fn with_decimals(mut self, decimals: u32) -> Self {
+        self.decimals = decimals;
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Capacity of the channels to the tasks, unbounded by default
+    pub fn with_channel_capacity(mut self, channel_capacity: Option<usize>) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    // Accounts to start from (e.g. loaded from a snapshot)
+    pub fn with_database(mut self, database: HashMap<AccountKey, Account>) -> Self {
+        for account in database.values() {
+            for (tx, transaction) in &account.transaction_history {
+                self.owners.insert(*tx, transaction.client_id);
+            }
+        }
+        self.transactions = Arc::new(self.owners.keys().copied().collect());
+        self.accounts = Arc::new(database.into_iter().collect());
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.recorder.progress = Some(progress);
+        self
+    }
+
+    pub fn with_rejects(mut self, rejects: Rejects) -> Self {
+        self.recorder.rejects = Some(rejects);
+        self
+    }
+
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.recorder.replay = Some(replay);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Counters of the operations processed by all the tasks
+    pub fn summary(&self) -> &Summary {
+        &self.recorder.summary
+    }
+
+    // Same as the sharded processor, the tx id is taken by the first operation even if it fails
+    fn is_repeated(&mut self, operation: &Operation) -> bool {
+        if !operation.type_operation.creates_transaction() {
+            return false;
+        }
+        let client_id = *self.owners.entry(operation.tx).or_insert(operation.client);
+        client_id != operation.client
+    }
+
+    // Whether the operation is a transfer to a client of another task
+    fn crosses_tasks(&self, operation: &Operation) -> bool {
+        operation.type_operation == TypeOperation::transfer
+            && operation.dest.is_some_and(|dest| {
+                dest as usize % self.tasks != operation.client as usize % self.tasks
+            })
+    }
+
+    // Waits until the tasks have applied everything sent to them so far. False if any of them
+    // stopped before
+    async fn barrier(senders: &[Sender<Job>]) -> bool {
+        let mut pending = Vec::with_capacity(senders.len());
+        for sender in senders {
+            let (done, wait) = oneshot::channel();
+            if sender.send(Job::Barrier(done)).await.is_err() {
+                return false;
+            }
+            pending.push(wait);
+        }
+        for wait in pending {
+            if wait.await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut senders = Vec::with_capacity(self.tasks);
+        let mut handles: Vec<JoinHandle<Result<Applier>>> = Vec::with_capacity(self.tasks);
+        for _ in 0..self.tasks {
+            let (sender, mut receiver) = channel::channel::<Job>(self.channel_capacity);
+            let mut applier = Applier {
+                accounts: self.accounts.clone(),
+                transactions: self.transactions.clone(),
+                decimals: self.decimals,
+                rounding: self.rounding,
+                policy: self.policy,
+                recorder: self.recorder.fork(),
+                sender: self.sender.clone(),
+            };
+            let mut shutdown = self.shutdown.clone();
+            handles.push(tokio::spawn(async move {
+                loop {
+                    let command = tokio::select! {
+                        biased;
+                        _ = shutdown_requested(&mut shutdown) => break,
+                        command = receiver.recv() => match command {
+                            Some(command) => command,
+                            None => break,
+                        },
+                    };
+                    match command {
+                        Job::Apply(operation) => applier.process_data(operation)?,
+                        Job::Repeated(operation) => applier.recorder.reject(
+                            &operation,
+                            &OperationStatus::RepeatedTransaction,
+                            &applier.policy,
+                        )?,
+                        Job::Query(client_id) => applier.answer_query(client_id).await?,
+                        // the router only waits for it, it does not matter if it is gone
+                        Job::Barrier(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+                Ok(applier)
+            }));
+            senders.push(sender);
+        }
+
+        // the queries go to the task of the client as well, so they see the operations before them
+        loop {
+            let command = tokio::select! {
+                biased;
+                _ = shutdown_requested(&mut self.shutdown) => break,
+                command = self.receiver.recv() => match command {
+                    Some(command) => command,
+                    None => break,
+                },
+            };
+            let mut crosses_tasks = false;
+            let (client_id, job) = match command {
+                reader::Command::CloseConnection => break,
+                reader::Command::Query(client_id) => (client_id, Job::Query(client_id)),
+                reader::Command::Data(operation) if self.is_repeated(&operation) => {
+                    (operation.client, Job::Repeated(operation))
+                }
+                reader::Command::Data(operation) => {
+                    crosses_tasks = self.crosses_tasks(&operation);
+                    (operation.client, Job::Apply(operation))
+                }
+            };
+            let task = client_id as usize % self.tasks;
+            // a task only stops early if it aborted (or on shutdown), its error is reported below
+            if crosses_tasks && !Self::barrier(&senders).await {
+                break;
+            }
+            if senders[task].send(job).await.is_err() {
+                break;
+            }
+            if crosses_tasks && !Self::barrier(&senders[task..=task]).await {
+                break;
+            }
+        }
+        // the tasks stop once their queues are empty
+        drop(senders);
+
+        // nothing is reported if any of the tasks aborted
+        for handle in handles {
+            let applier = handle.await??;
+            self.recorder.summary.merge(&applier.recorder.summary);
+            self.recorder
+                .rejected_accounts
+                .extend(applier.recorder.rejected_accounts);
+        }
+        let (decimals, rounding) = (self.decimals, self.rounding);
+        for key in self.recorder.rejected_accounts.drain() {
+            self.accounts
+                .entry(key.clone())
+                .or_insert_with(|| Account::build_empty(&key, decimals, rounding));
+        }
+        self.recorder.flush()?;
+        // every task is over, so nothing else holds the accounts
+        let database: HashMap<AccountKey, Account> = self
+            .accounts
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        if let Some(path) = &self.snapshot {
+            snapshot::save(path, database.values())?;
+        }
+        report(&self.sender, database.into_values()).await
+    }
+}
+
+// What the tasks of the concurrent processor receive
+#[derive(Debug)]
+enum Job {
+    Apply(Operation),
+    // its tx id was already created by another client
+    Repeated(Operation),
+    Query(u16),
+    // answered once everything before it is applied
+    Barrier(oneshot::Sender<()>),
+}
+
+// One of the tasks of the concurrent processor. An account is only locked while an operation
+// is applied to it, and never along with another one (two of them may be in the same shard of
+// the map): the destination of a transfer is taken out of the map instead
+struct Applier {
+    accounts: Arc<DashMap<AccountKey, Account>>,
+    transactions: Arc<DashSet<u32>>,
+    decimals: u32,
+    rounding: Rounding,
+    policy: Policy,
+    recorder: Recorder,
+    // where the answers to the queries go
+    sender: Sender<writer::Command>,
+}
+
+impl Applier {
+    // Same checks as the engine. Only the task of the client can create its tx ids (see
+    // ConcurrentProcessor::is_repeated), they are taken before the operation is applied and
+    // given back if it fails
+    fn apply(&self, operation: Operation) -> OperationStatus {
+        let tx = operation.tx;
+        let type_operation = operation.type_operation;
+        let reference = operation.reference.clone();
+        let key = AccountKey::build(operation.client, operation.currency.clone());
+        if !self.accounts.contains_key(&key) && type_operation != TypeOperation::deposit {
+            return OperationStatus::NonExistingAccount;
+        }
+        let creates_transaction = type_operation.creates_transaction();
+        if creates_transaction && !self.transactions.insert(tx) {
+            return OperationStatus::RepeatedTransaction;
+        }
+        let status = match type_operation {
+            TypeOperation::transfer => self.transfer(&key, operation),
+            _ => {
+                let mut account = self
+                    .accounts
+                    .entry(key.clone())
+                    .or_insert_with(|| Account::build_empty(&key, self.decimals, self.rounding));
+                let status = Engine::execute_operation(&mut account, operation, &self.policy);
+                if Engine::record(&mut account, tx, &status) {
+                    account.debug_assert_invariants();
+                }
+                status
+            }
+        };
+        if creates_transaction && !status.applied() {
+            self.transactions.remove(&tx);
+        }
+        if let (OperationStatus::Successful(_), Some(reference)) = (&status, reference) {
+            if let Some(mut account) = self.accounts.get_mut(&key) {
+                account.index_reference(reference, tx);
+            }
+        }
+        status
+    }
+
+    // Same as the engine. No other task can reach the destination while it is out of the map:
+    // it is either a client of this task, or the other tasks wait for the transfer (see
+    // ConcurrentProcessor::run)
+    fn transfer(&self, key: &AccountKey, operation: Operation) -> OperationStatus {
+        let dest_key = match Engine::transfer_dest(&operation) {
+            Some(dest_key) => dest_key,
+            None => return OperationStatus::InvalidTransfer,
+        };
+        let mut dest = match self.accounts.remove(&dest_key) {
+            Some((_, dest)) => dest,
+            None => return OperationStatus::NonExistingAccount,
+        };
+        let tx = operation.tx;
+        let status = {
+            let mut source = self
+                .accounts
+                .get_mut(key)
+                .expect("the source account exists");
+            let status = Engine::execute_transfer_between(&mut source, &mut dest, operation, true);
+            Engine::record(&mut source, tx, &status);
+            status
+        };
+        self.accounts.insert(dest_key, dest);
+        status
+    }
+
+    fn process_data(&mut self, operation: Operation) -> Result<()> {
+        let status = self.apply(operation.clone());
+        if !status.applied() {
+            return self.recorder.reject(&operation, &status, &self.policy);
+        }
+        self.recorder.record_applied(&operation)
+    }
+
+    // The accounts are copied first, so none of them is locked while waiting for the writer
+    async fn answer_query(&self, client_id: u16) -> Result<()> {
+        let accounts: Vec<Account> = self
+            .accounts
+            .iter()
+            .filter(|entry| entry.key().client_id == client_id)
+            .map(|entry| entry.value().clone())
+            .collect();
+        for account in accounts {
+            self.sender.send(writer::Command::Answer(account)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::{Account, AccountKey};
+    use crate::channel;
+    use crate::concurrent::ConcurrentProcessor;
+    use crate::parser::{Operation, TypeOperation};
+    use crate::processor::Processor;
+    use crate::{reader, writer};
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    // Every withdrawal spends the deposit right before it, so it only succeeds if the
+    // operations of the client are applied in order
+    fn ordered_operations(clients: u16, rounds: u32) -> Vec<Operation> {
+        let mut list_operations = vec![];
+        for round in 0..rounds {
+            for client in 0..clients {
+                let tx = (round * clients as u32 + client as u32) * 2;
+                list_operations.push(Operation::new(
+                    TypeOperation::deposit,
+                    client,
+                    tx,
+                    Some(1.5),
+                ));
+                list_operations.push(Operation::new(
+                    TypeOperation::withdrawal,
+                    client,
+                    tx + 1,
+                    Some(1.5),
+                ));
+            }
+        }
+        list_operations
+    }
+
+    async fn send_all(list_operations: Vec<Operation>) -> channel::Receiver<reader::Command> {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        for operation in list_operations {
+            assert!(sender_operations
+                .send(reader::Command::Data(operation))
+                .await
+                .is_ok());
+        }
+        assert!(sender_operations
+            .send(reader::Command::CloseConnection)
+            .await
+            .is_ok());
+        receiver_operations
+    }
+
+    async fn receive_all(mut receiver_results: channel::Receiver<writer::Command>) -> Vec<Account> {
+        let mut results = vec![];
+        while let Some(writer::Command::Data(account)) = receiver_results.recv().await {
+            results.push(account);
+        }
+        results.sort_by_key(|account| account.client_id());
+        results
+    }
+
+    // The accounts with the serial processor and with the concurrent one, which reject the same
+    // operations for the same reasons. Both start from the given accounts
+    async fn run_both(
+        list_operations: Vec<Operation>,
+        database: HashMap<AccountKey, Account>,
+        tasks: usize,
+    ) -> (Vec<Account>, Vec<Account>) {
+        let (sender_results, receiver_results) = channel::channel::<writer::Command>(None);
+        let mut processor =
+            Processor::build(send_all(list_operations.clone()).await, sender_results)
+                .with_database(database.clone());
+        assert!(processor.run().await.is_ok());
+        let summary = processor.summary().clone();
+        let serial = receive_all(receiver_results).await;
+
+        let (sender_results, receiver_results) = channel::channel::<writer::Command>(None);
+        let mut processor =
+            ConcurrentProcessor::build(send_all(list_operations).await, sender_results, tasks)
+                .with_database(database);
+        assert!(processor.run().await.is_ok());
+        assert_eq!(processor.summary(), &summary);
+        (serial, receive_all(receiver_results).await)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_client_order() {
+        let (sender_results, receiver_results) = channel::channel::<writer::Command>(None);
+        let mut processor = ConcurrentProcessor::build(
+            send_all(ordered_operations(16, 500)).await,
+            sender_results,
+            4,
+        );
+        assert!(processor.run().await.is_ok());
+        assert_eq!(processor.summary().applied(), 16 * 500 * 2);
+        assert_eq!(processor.summary().total_ignored(), 0);
+        let accounts = receive_all(receiver_results).await;
+        assert_eq!(accounts.len(), 16);
+        for account in accounts {
+            assert_eq!(account.total(), "0.0000");
+            assert!(account.check_invariants());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_same_as_serial() {
+        let mut list_operations = ordered_operations(8, 10);
+        for client in 0..8u16 {
+            let tx = 1000 + client as u32 * 10;
+            list_operations.extend([
+                Operation::new(TypeOperation::deposit, client, tx, Some(100.0)),
+                // a tx id of another client
+                Operation::new(TypeOperation::deposit, client, 0, Some(1.0)),
+                Operation::new(TypeOperation::withdrawal, client, tx + 1, Some(1000.0)),
+                Operation::new(TypeOperation::dispute, client, tx, None),
+                Operation::new(TypeOperation::resolve, client, tx, None),
+                // between clients of different tasks, which may not have applied any operation
+                // yet: their accounts exist from the start. None to the last client
+                Operation::new(TypeOperation::transfer, client, tx + 2, Some(2.5))
+                    .with_dest((client + 1) % 7),
+                Operation::new(TypeOperation::transfer, client, tx + 3, Some(1.0)).with_dest(99),
+            ]);
+        }
+        // the lock does not race with a transfer from another task, the last client receives none
+        list_operations.push(Operation::new(TypeOperation::dispute, 7, 1070, None));
+        list_operations.push(Operation::new(TypeOperation::chargeback, 7, 1070, None));
+        let database = (0..8)
+            .map(|client| (AccountKey::from(client), Account::build(client)))
+            .collect();
+        let (serial, concurrent) = run_both(list_operations, database, 4).await;
+        assert_eq!(serial.len(), 8);
+        assert_eq!(serial, concurrent);
+    }
+
+    // Each client spends what the previous one (owned by another task) just transferred to it,
+    // and some of them are locked in between, so the results only match the serial ones if
+    // every transfer between tasks is applied in the order of the input
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_transfers_between_tasks() {
+        let clients = 8u16;
+        let mut list_operations = vec![];
+        for round in 0..200u32 {
+            for client in 0..clients {
+                let tx = (round * clients as u32 + client as u32) * 4;
+                let dest = (client + 1) % clients;
+                list_operations.extend([
+                    Operation::new(TypeOperation::deposit, client, tx, Some(1.0)),
+                    Operation::new(TypeOperation::transfer, client, tx + 1, Some(1.0))
+                        .with_dest(dest),
+                    Operation::new(TypeOperation::withdrawal, dest, tx + 2, Some(1.0)),
+                ]);
+                if round == 100 && client % 3 == 0 {
+                    list_operations.extend([
+                        Operation::new(TypeOperation::dispute, client, tx, None),
+                        Operation::new(TypeOperation::chargeback, client, tx, None),
+                    ]);
+                }
+            }
+        }
+        let database = (0..clients)
+            .map(|client| (AccountKey::from(client), Account::build(client)))
+            .collect();
+        let (serial, concurrent) = run_both(list_operations, database, 4).await;
+        assert_eq!(serial.len(), clients as usize);
+        assert_eq!(serial, concurrent);
+    }
+
+    // A transfer which fails several checks is rejected for the first one, as the engine does
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_transfer_reasons() {
+        let mut list_operations = vec![
+            Operation::new(TypeOperation::deposit, 0, 1, Some(5.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+            Operation::new(TypeOperation::dispute, 0, 1, None),
+            Operation::new(TypeOperation::chargeback, 0, 1, None),
+        ];
+        // client 0 is locked, client 4 is owned by the same task and 9 does not exist
+        for (tx, client, amount, dest) in [
+            (10, 0, None, 1),
+            (11, 1, None, 0),
+            (12, 1, Some(0.0), 0),
+            (13, 4, Some(1.0), 1),
+            (14, 1, None, 1),
+            (15, 1, None, 9),
+            (16, 1, Some(50.0), 4),
+            (17, 1, Some(1.0), 4),
+        ] {
+            list_operations
+                .push(Operation::new(TypeOperation::transfer, client, tx, amount).with_dest(dest));
+        }
+        let database = HashMap::from([(AccountKey::from(4), Account::build(4))]);
+        let (serial, concurrent) = run_both(list_operations, database, 4).await;
+        assert_eq!(serial, concurrent);
+    }
+
+    // Not a test: cargo test --release bench_concurrent -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_concurrent() {
+        let list_operations = ordered_operations(1000, 500);
+        let receiver_operations = send_all(list_operations.clone()).await;
+        let start = Instant::now();
+        let (sender_results, receiver_results) = channel::channel::<writer::Command>(None);
+        let mut processor = Processor::build(receiver_operations, sender_results);
+        assert!(processor.run().await.is_ok());
+        drop(receiver_results);
+        eprintln!("serial: {:?}", start.elapsed());
+        for tasks in [2, 4, 8] {
+            let receiver_operations = send_all(list_operations.clone()).await;
+            let start = Instant::now();
+            let (sender_results, receiver_results) = channel::channel::<writer::Command>(None);
+            let mut processor =
+                ConcurrentProcessor::build(receiver_operations, sender_results, tasks);
+            assert!(processor.run().await.is_ok());
+            drop(receiver_results);
+            eprintln!("{} tasks: {:?}", tasks, start.elapsed());
+        }
+    }
+}
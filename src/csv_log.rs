@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type CsvWriter = csv::Writer<Box<dyn io::Write + Send>>;
+
+// CSV shared by all the processors (e.g. the workers), a row at a time. Each of them only
+// writes in order its own rows, so with several workers the rows are only in order within
+// each client. The rejects file and the replay log are built on it
+#[derive(Clone)]
+pub(crate) struct CsvLog {
+    writer: Arc<Mutex<CsvWriter>>,
+    // how the errors refer to it
+    name: &'static str,
+}
+
+impl CsvLog {
+    // The header is written right away, so the file has it even if no row is
+    pub(crate) fn build<W: io::Write + Send + 'static>(
+        destination: W,
+        header: &[&str],
+        name: &'static str,
+    ) -> Result<Self> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Box::new(destination) as Box<dyn io::Write + Send>);
+        writer.write_record(header)?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            name,
+        })
+    }
+
+    pub(crate) fn create(path: &Path, header: &[&str], name: &'static str) -> Result<Self> {
+        let file = fs::File::create(path)
+            .with_context(|| format!("Cannot create the {} {}", name, path.display()))?;
+        Self::build(io::BufWriter::new(file), header, name)
+    }
+
+    pub(crate) fn record<T: Serialize>(&self, row: T) -> Result<()> {
+        self.lock()?.serialize(row)?;
+        Ok(())
+    }
+
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.lock()?.flush()?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, CsvWriter>> {
+        self.writer
+            .lock()
+            .map_err(|_| anyhow!("The {} is poisoned", self.name))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::channel;
+    use crate::processor::Processor;
+    use crate::reader::{Reader, Source};
+    use crate::{reader, writer};
+    use anyhow::Result;
+    use std::path::{Path, PathBuf};
+    use std::{env, fs, io};
+
+    // Temporary file of a test, apart from the other tests
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("atm_{}_{}.csv", name, std::process::id()))
+    }
+
+    // Read and remove the file
+    pub(crate) fn take(path: &Path) -> String {
+        let written = fs::read_to_string(path).expect("cannot read the log");
+        fs::remove_file(path).expect("cannot remove the log");
+        written
+    }
+
+    // Process the data with a log created by `create`, which `attach` hands over to the
+    // processor. Returns the number of skipped rows and what was written to the log
+    pub(crate) async fn run_with_log<L>(
+        name: &str,
+        data: &str,
+        create: impl FnOnce(&Path) -> Result<L>,
+        attach: impl FnOnce(Processor, L) -> Processor,
+    ) -> (u64, String) {
+        let path = temp_path(name);
+        let log = create(&path).expect("cannot create the log");
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, _receiver_results) = channel::channel::<writer::Command>(None);
+        let reader = Reader::build(
+            sender_operations,
+            Source::Stream(Box::new(io::Cursor::new(data.as_bytes().to_vec()))),
+            Default::default(),
+        );
+        let mut processor = attach(Processor::build(receiver_operations, sender_results), log);
+        let skipped = reader.run().await.expect("the reader failed");
+        assert!(processor.run().await.is_ok());
+        (skipped, take(&path))
+    }
+}
@@ -0,0 +1,402 @@
+use crate::account::{Account, AccountKey, Rounding};
+use crate::parser::{Operation, TypeOperation};
+use crate::processor::{OperationStatus, Policy};
+use crate::store::AccountStore;
+use crate::transaction::Transaction;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+mod executors;
+
+// Where the operations are applied to the accounts, without any channel: the processor wraps
+// it, but it can also be driven one operation at a time (e.g. from a REPL) and the accounts
+// looked at in between
+pub struct Engine<S = HashMap<AccountKey, Account>> {
+    // it represents a SQL database table, in a real scenario it would be a database access
+    database: S,
+    // tx ids of all the clients. It is the only place where the uniqueness of the tx ids is
+    // checked (the executors rely on it), the histories are only looked up by client
+    transactions: HashSet<u32>,
+    // number of decimal places the accounts are created with
+    decimals: u32,
+    // how the amounts are scaled to those decimals
+    rounding: Rounding,
+    policy: Policy,
+    // how many finalized transactions an account keeps, all of them by default
+    history_retention: Option<usize>,
+    // tx ids of the finalized transactions of each account, oldest first. Only tracked with a
+    // retention
+    finalized: HashMap<AccountKey, VecDeque<u32>>,
+    // whether the transactions are written to the histories, see with_history
+    history: bool,
+}
+
+impl Engine {
+    pub fn build() -> Self {
+        Self::build_with_store(HashMap::new())
+    }
+
+    // Write the transaction of an applied operation to the history of its account. False if it
+    // was not applied, nothing is written then
+    pub(crate) fn record(account: &mut Account, tx: u32, status: &OperationStatus) -> bool {
+        match status {
+            OperationStatus::Successful(new_transaction) => {
+                account
+                    .transaction_history
+                    .insert(tx, new_transaction.clone());
+            }
+            OperationStatus::UpdateTransaction(tx, transaction) => {
+                *account
+                    .transaction_history
+                    .get_mut(tx)
+                    .expect("unexpected error") = transaction.clone();
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl<S: AccountStore> Engine<S> {
+    // The accounts are kept in the given store, which may already have some
+    pub fn build_with_store(store: S) -> Self {
+        Self {
+            transactions: store
+                .iter()
+                .flat_map(|account| account.transaction_history.keys().copied())
+                .collect(),
+            database: store,
+            decimals: Account::DEFAULT_DECIMALS,
+            rounding: Default::default(),
+            policy: Default::default(),
+            history_retention: None,
+            finalized: Default::default(),
+            history: true,
+        }
+    }
+
+    pub fn with_decimals(mut self, decimals: u32) -> Self {
+        self.decimals = decimals;
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Once an account has more than this number of finalized transactions (see
+    // Transaction::finalized), the oldest are evicted from its history and their tx ids
+    // forgotten, so a later operation can reuse them. The deposits which can still be disputed
+    // are always kept
+    pub fn with_history_retention(mut self, history_retention: usize) -> Self {
+        self.history_retention = Some(history_retention);
+        self.track_finalized();
+        self
+    }
+
+    // Without history only the tx ids of the transactions are kept, to reject the repeated
+    // ones: the accounts take much less memory on a huge input, but nothing can be disputed
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    // Accounts to start from (e.g. loaded from a snapshot). The tx ids of their histories are
+    // already taken, and their transactions can be disputed
+    pub fn with_database(self, database: S) -> Self {
+        let mut engine = Self {
+            decimals: self.decimals,
+            rounding: self.rounding,
+            policy: self.policy,
+            history_retention: self.history_retention,
+            history: self.history,
+            ..Self::build_with_store(database)
+        };
+        engine.track_finalized();
+        engine
+    }
+
+    // Accounts as they are after the operations applied so far
+    pub fn accounts(&self) -> &S {
+        &self.database
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // The account, created empty (as a deposit would) if it does not exist
+    pub fn open_account(&mut self, key: &AccountKey) -> &mut Account {
+        let (decimals, rounding) = (self.decimals, self.rounding);
+        self.database
+            .get_or_create(key, || Account::build_empty(key, decimals, rounding))
+    }
+
+    // The finalized transactions already in the accounts, whose order is unknown: the lowest tx
+    // ids are taken as the oldest
+    fn track_finalized(&mut self) {
+        if self.history_retention.is_none() {
+            return;
+        }
+        self.finalized = self
+            .database
+            .iter()
+            .map(|account| {
+                let finalized = account
+                    .transaction_history
+                    .iter()
+                    .filter(|(_, transaction)| transaction.finalized())
+                    .map(|(tx, _)| *tx)
+                    .collect();
+                (account.key(), finalized)
+            })
+            .collect();
+        let keys: Vec<AccountKey> = self.finalized.keys().cloned().collect();
+        for key in keys {
+            self.evict(&key);
+        }
+    }
+
+    // Called once a transaction of the account is finalized
+    fn finalize(&mut self, key: &AccountKey, tx: u32) {
+        if self.history_retention.is_some() {
+            self.finalized.entry(key.clone()).or_default().push_back(tx);
+            self.evict(key);
+        }
+    }
+
+    // The oldest finalized transactions of the account beyond the retention are forgotten
+    fn evict(&mut self, key: &AccountKey) {
+        let (retention, finalized) = match (self.history_retention, self.finalized.get_mut(key)) {
+            (Some(retention), Some(finalized)) => (retention, finalized),
+            _ => return,
+        };
+        while finalized.len() > retention {
+            let oldest = finalized.pop_front().expect("more than retention");
+            if let Some(account) = self.database.get_mut(key) {
+                // the tx id may have been reused since it was evicted from the other account
+                // of a transfer
+                let history = &mut account.transaction_history;
+                if history.get(&oldest).is_some_and(Transaction::finalized) {
+                    history.remove(&oldest);
+                    self.transactions.remove(&oldest);
+                    account.references.retain(|_, tx| *tx != oldest);
+                }
+            }
+        }
+    }
+
+    // Apply a single operation. Successful and UpdateTransaction mean it was applied, any other
+    // status is why it was rejected
+    pub fn apply(&mut self, operation: Operation) -> OperationStatus {
+        let tx = operation.tx;
+        let type_operation = operation.type_operation;
+        let reference = operation.reference.clone();
+        // every operation works with the account of its currency
+        let key = AccountKey::build(operation.client, operation.currency.clone());
+        // the account a transfer credits
+        let dest_key = operation
+            .dest
+            .map(|dest| AccountKey::build(dest, operation.currency.clone()));
+        // there is no transaction to dispute without history, nor to hold a disputed deposit
+        let disputed = type_operation.references_transaction()
+            || (type_operation == TypeOperation::deposit && operation.disputed);
+        if !self.history && disputed {
+            return OperationStatus::NoHistory;
+        }
+        // it is only possible to create an account with a deposit
+        if !self.database.contains(&key) && type_operation != TypeOperation::deposit {
+            return OperationStatus::NonExistingAccount;
+        }
+        // tx ids are globally unique (across all the clients): if the transaction already exists, we exit
+        // This is the only check, for deposits, withdrawals and transfers alike, whatever created the tx
+        if self.transactions.contains(&tx) && type_operation.creates_transaction() {
+            return OperationStatus::RepeatedTransaction;
+        }
+        let status = match type_operation {
+            // a transfer involves two accounts
+            TypeOperation::transfer => self.execute_transfer(operation),
+            _ => {
+                let policy = self.policy;
+                let account = self.open_account(&key);
+                // the executors do not depend on the store
+                Engine::execute_operation(account, operation, &policy)
+            }
+        };
+        let account = self
+            .database
+            .get_mut(&key)
+            .expect("the account exists at this point");
+        let recorded = match status {
+            OperationStatus::Successful(_) if !self.history => true,
+            _ => Engine::record(account, tx, &status),
+        };
+        if !recorded {
+            return status;
+        }
+        let tx = match status {
+            OperationStatus::Successful(_) => {
+                self.transactions.insert(tx);
+                if let (Some(reference), true) = (reference, self.history) {
+                    account.index_reference(reference, tx);
+                }
+                tx
+            }
+            // it may have targeted the transaction by its reference
+            OperationStatus::UpdateTransaction(tx, _) => tx,
+            _ => tx,
+        };
+        account.debug_assert_invariants();
+        let finalized = account
+            .transaction_history
+            .get(&tx)
+            .is_some_and(Transaction::finalized);
+        if finalized {
+            self.finalize(&key, tx);
+            if let (TypeOperation::transfer, Some(dest_key)) = (type_operation, dest_key) {
+                self.finalize(&dest_key, tx);
+            }
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::AccountKey;
+    use crate::engine::Engine;
+    use crate::parser::{Operation, TypeOperation};
+    use crate::processor::{OperationStatus, Policy};
+
+    #[test]
+    fn test_apply() {
+        let mut engine = Engine::build().with_decimals(2).with_policy(Policy {
+            strict: true,
+            ..Default::default()
+        });
+        // (operation, whether it is applied, available and held funds right after it)
+        for (operation, applied, available, held) in [
+            (
+                Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+                true,
+                "10.00",
+                "0.00",
+            ),
+            (
+                Operation::new(TypeOperation::withdrawal, 1, 2, Some(2.5)),
+                true,
+                "7.50",
+                "0.00",
+            ),
+            (
+                Operation::new(TypeOperation::withdrawal, 1, 3, Some(20.0)),
+                false,
+                "7.50",
+                "0.00",
+            ),
+            (
+                Operation::new(TypeOperation::dispute, 1, 1, None),
+                true,
+                "-2.50",
+                "10.00",
+            ),
+            (
+                Operation::new(TypeOperation::resolve, 1, 1, None),
+                true,
+                "7.50",
+                "0.00",
+            ),
+            (
+                Operation::new(TypeOperation::deposit, 1, 2, Some(1.0)),
+                false,
+                "7.50",
+                "0.00",
+            ),
+        ] {
+            assert_eq!(engine.apply(operation).applied(), applied);
+            let account = &engine.accounts()[&AccountKey::from(1)];
+            assert_eq!(account.available_funds(), available);
+            assert_eq!(account.held_funds(), held);
+        }
+        assert_eq!(
+            engine.accounts()[&AccountKey::from(1)]
+                .transaction_history
+                .len(),
+            2
+        );
+
+        // the strict policy is up to whoever drives the engine, the status only tells why
+        assert!(matches!(
+            engine.apply(Operation::new(TypeOperation::dispute, 2, 1, None)),
+            OperationStatus::NonExistingAccount
+        ));
+        assert_eq!(engine.accounts().len(), 1);
+    }
+
+    #[test]
+    fn test_history_retention() {
+        let mut engine = Engine::build().with_history_retention(2);
+        assert!(engine
+            .apply(Operation::new(TypeOperation::deposit, 1, 1, Some(100.0)))
+            .applied());
+        for tx in 2..1000 {
+            assert!(engine
+                .apply(Operation::new(TypeOperation::withdrawal, 1, tx, Some(0.01)))
+                .applied());
+        }
+        let history = |engine: &Engine| -> Vec<u32> {
+            engine.accounts()[&AccountKey::from(1)]
+                .transaction_history
+                .keys()
+                .copied()
+                .collect()
+        };
+        // the deposit can still be disputed, so it is kept however old it is
+        assert_eq!(history(&engine), vec![1, 998, 999]);
+        assert!(engine
+            .apply(Operation::new(TypeOperation::dispute, 1, 1, None))
+            .applied());
+        assert!(engine
+            .apply(Operation::new(TypeOperation::resolve, 1, 1, None))
+            .applied());
+        assert_eq!(
+            engine.accounts()[&AccountKey::from(1)].available_funds(),
+            "90.0200"
+        );
+
+        // once charged back the deposit is finalized and evicted like the others
+        assert!(engine
+            .apply(Operation::new(TypeOperation::dispute, 1, 1, None))
+            .applied());
+        assert!(engine
+            .apply(Operation::new(TypeOperation::chargeback, 1, 1, None))
+            .applied());
+        assert_eq!(history(&engine), vec![1, 999]);
+        // an evicted tx id is forgotten
+        assert!(engine
+            .apply(Operation::new(TypeOperation::deposit, 2, 2, Some(1.0)))
+            .applied());
+
+        // without retention nothing is evicted
+        let mut engine = Engine::build();
+        for tx in 1..10 {
+            assert!(engine
+                .apply(Operation::new(TypeOperation::deposit, 1, tx, Some(1.0)))
+                .applied());
+            assert!(engine
+                .apply(Operation::new(
+                    TypeOperation::withdrawal,
+                    1,
+                    tx + 100,
+                    Some(1.0)
+                ))
+                .applied());
+        }
+        assert_eq!(history(&engine).len(), 18);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::account::{Account, AccountKey, FundsError};
+use crate::engine::Engine;
+use crate::parser::{Operation, TypeOperation};
+use crate::processor::{OperationStatus, Policy};
+use crate::store::AccountStore;
+use crate::transaction::{DisputeState, Settlement, Transaction, TransactionType};
+
+impl Engine {
+    fn execute_deposit(account: &mut Account, operation: Operation) -> OperationStatus {
+        if let Some(amount) = operation.amount {
+            // a zero amount moves nothing, so it is rejected as malformed
+            if amount == 0.0 {
+                return OperationStatus::ZeroAmount;
+            }
+            // a deposit which arrives disputed goes straight to the held funds
+            let (funds, dispute_state) = if operation.disputed {
+                (account.add_held_funds(amount), DisputeState::Disputed)
+            } else {
+                (account.add_funds(amount), DisputeState::None)
+            };
+            match funds {
+                Ok(()) => OperationStatus::Successful(Transaction {
+                    client_id: account.client_id(),
+                    amount,
+                    type_transaction: TransactionType::Deposit,
+                    dispute_state,
+                    settled: Default::default(),
+                }),
+                Err(error) => error.into(),
+            }
+        } else {
+            OperationStatus::EmptyAmount
+        }
+    }
+
+    // The policy may allow the available funds to go negative, down to its overdraft limit, and
+    // cap what the account withdraws in the run
+    fn execute_withdrawal(
+        account: &mut Account,
+        operation: Operation,
+        policy: &Policy,
+    ) -> OperationStatus {
+        if let Some(amount) = operation.amount {
+            if amount == 0.0 {
+                return OperationStatus::ZeroAmount;
+            }
+            if let Some(cap) = policy.max_withdrawal_per_client {
+                if !account.within_withdrawal_cap(amount, cap) {
+                    return OperationStatus::LimitExceeded;
+                }
+            }
+            match account.overdraw_funds(amount, policy.overdraft_limit) {
+                Ok(()) => {
+                    account.record_withdrawal(amount);
+                    OperationStatus::Successful(Transaction {
+                        client_id: account.client_id(),
+                        amount,
+                        type_transaction: TransactionType::Withdrawal,
+                        dispute_state: DisputeState::None,
+                        settled: Default::default(),
+                    })
+                }
+                Err(error) => error.into(),
+            }
+        } else {
+            OperationStatus::EmptyAmount
+        }
+    }
+
+    // Transaction referenced by a dispute, resolve or chargeback (with its tx), as long as it
+    // belongs to the client of the operation: the tx of another client is treated as a non
+    // existing one. The tx goes first, the reference is only looked up if there is no such tx.
+    // It is copied (not the whole account with its history) so the account can be modified
+    // while looking at it
+    fn owned_transaction(account: &Account, operation: &Operation) -> Option<(u32, Transaction)> {
+        if account.client_id() != operation.client {
+            return None;
+        }
+        let tx = if account.transaction_history.contains_key(&operation.tx) {
+            operation.tx
+        } else {
+            *account.references.get(operation.reference.as_ref()?)?
+        };
+        account
+            .transaction_history
+            .get(&tx)
+            .filter(|transaction| transaction.client_id == operation.client)
+            .map(|transaction| (tx, transaction.clone()))
+    }
+
+    fn execute_dispute(account: &mut Account, operation: Operation) -> OperationStatus {
+        if let Some((tx, transaction)) = Self::owned_transaction(account, &operation) {
+            // only deposits can be disputed. A transaction already under dispute cannot be
+            // disputed again, otherwise its funds would be held twice
+            if transaction.type_transaction != TransactionType::Deposit
+                || !matches!(
+                    transaction.dispute_state,
+                    DisputeState::None | DisputeState::Resolved
+                )
+            {
+                return OperationStatus::DisputeError;
+            }
+            // the deposit may have been spent already, then the account owes the difference
+            if let Err(error) = account.hold_funds(transaction.amount) {
+                return error.into();
+            }
+            OperationStatus::UpdateTransaction(
+                tx,
+                Transaction {
+                    dispute_state: DisputeState::Disputed,
+                    ..transaction
+                },
+            )
+        } else {
+            OperationStatus::NonExistingTx
+        }
+    }
+
+    // Part of the held funds a resolve or chargeback settles: all of them, unless the operation
+    // carries an amount (only with partial disputes, see execute_operation)
+    fn settled_amount(
+        account: &Account,
+        transaction: &Transaction,
+        operation: &Operation,
+    ) -> Result<f64, OperationStatus> {
+        match operation.amount {
+            None => Ok(transaction.held_amount()),
+            Some(0.0) => Err(OperationStatus::ZeroAmount),
+            Some(amount) if !account.covers(transaction, amount) => {
+                Err(OperationStatus::DisputeError)
+            }
+            Some(amount) => Ok(amount),
+        }
+    }
+
+    // The dispute is over once nothing is held anymore: charged back if any part of it was,
+    // otherwise resolved. Until then the transaction stays disputed with the rest held
+    fn settle(account: &Account, transaction: Transaction, settled: Settlement) -> Transaction {
+        let transaction = Transaction {
+            settled,
+            ..transaction
+        };
+        if account.held_by(&transaction) > 0 {
+            return transaction;
+        }
+        let dispute_state = if transaction.settled.charged_back > 0.0 {
+            DisputeState::ChargedBack
+        } else {
+            DisputeState::Resolved
+        };
+        Transaction {
+            dispute_state,
+            settled: Settlement::default(),
+            ..transaction
+        }
+    }
+
+    // The held funds of the account do not cover what the disputed transaction holds, which
+    // should not happen (settled_amount is checked against the transaction). It may come from
+    // a snapshot edited by hand
+    fn uncovered(account: &Account, tx: u32) -> OperationStatus {
+        tracing::error!(
+            client = account.client_id(),
+            tx,
+            held = %account.held_funds(),
+            "the held funds do not cover the disputed transaction"
+        );
+        OperationStatus::Unknown
+    }
+
+    fn execute_resolve(account: &mut Account, operation: Operation) -> OperationStatus {
+        if let Some((tx, transaction)) = Self::owned_transaction(account, &operation) {
+            // only a transaction currently under dispute can be resolved or charged back
+            if !transaction.disputed() {
+                return OperationStatus::DisputeError;
+            }
+            let amount = match Self::settled_amount(account, &transaction, &operation) {
+                Ok(amount) => amount,
+                Err(status) => return status,
+            };
+            match account.unblock_funds(amount) {
+                Ok(()) => {}
+                Err(FundsError::InsufficientFunds) => return Self::uncovered(account, tx),
+                Err(error) => return error.into(),
+            }
+            let settled = Settlement {
+                resolved: transaction.settled.resolved + amount,
+                ..transaction.settled.clone()
+            };
+            OperationStatus::UpdateTransaction(tx, Self::settle(account, transaction, settled))
+        } else {
+            OperationStatus::NonExistingTx
+        }
+    }
+
+    // Even a partial chargeback locks the account, the rest of the dispute can only go on with
+    // allow_dispute_when_locked
+    fn execute_chargeback(account: &mut Account, operation: Operation) -> OperationStatus {
+        if let Some((tx, transaction)) = Self::owned_transaction(account, &operation) {
+            // only a transaction currently under dispute can be charged back, so a charged back
+            // transaction is final: neither the funds nor the lock are touched again
+            if !transaction.disputed() {
+                return OperationStatus::DisputeError;
+            }
+            let amount = match Self::settled_amount(account, &transaction, &operation) {
+                Ok(amount) => amount,
+                Err(status) => return status,
+            };
+            match account.retire_blocked_funds(amount) {
+                Ok(()) => {}
+                Err(FundsError::InsufficientFunds) => return Self::uncovered(account, tx),
+                Err(error) => return error.into(),
+            }
+            account.lock();
+            let settled = Settlement {
+                charged_back: transaction.settled.charged_back + amount,
+                ..transaction.settled.clone()
+            };
+            OperationStatus::UpdateTransaction(tx, Self::settle(account, transaction, settled))
+        } else {
+            OperationStatus::NonExistingTx
+        }
+    }
+
+    // A locked account accepts the operations again, its funds and history are untouched
+    fn execute_unlock(
+        account: &mut Account,
+        operation: Operation,
+        policy: &Policy,
+    ) -> OperationStatus {
+        if !policy.allow_unlock {
+            return OperationStatus::UnlockNotAllowed;
+        }
+        if operation.amount.is_some() {
+            return OperationStatus::UnexpectedAmount;
+        }
+        account.unlock();
+        OperationStatus::Unlocked
+    }
+
+    // Account a transfer credits, None without a destination or to the source itself. The funds
+    // stay in the same currency
+    pub(crate) fn transfer_dest(operation: &Operation) -> Option<AccountKey> {
+        match operation.dest {
+            Some(dest_id) if dest_id != operation.client => {
+                Some(AccountKey::build(dest_id, operation.currency.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // A transfer between both accounts, however they are kept (see also the concurrent
+    // processor). The source gets its side of the history once the status is recorded, the
+    // destination gets it here
+    pub(crate) fn execute_transfer_between(
+        source: &mut Account,
+        dest: &mut Account,
+        operation: Operation,
+        history: bool,
+    ) -> OperationStatus {
+        let (client_id, tx) = (operation.client, operation.tx);
+        let status = Engine::transfer_funds(source, dest, operation);
+        if let (OperationStatus::Successful(transfer), true) = (&status, history) {
+            dest.transaction_history.insert(
+                tx,
+                Transaction {
+                    client_id,
+                    type_transaction: TransactionType::TransferIn,
+                    ..transfer.clone()
+                },
+            );
+        }
+        status
+    }
+
+    // The source account is debited and the destination one credited. If the credit fails, the
+    // debit is rolled back, so the transfer is applied completely or not at all. Neither history
+    // is written here
+    fn transfer_funds(
+        source: &mut Account,
+        dest: &mut Account,
+        operation: Operation,
+    ) -> OperationStatus {
+        if source.locked || dest.locked {
+            return OperationStatus::AccountLocked;
+        }
+        let amount = match operation.amount {
+            Some(amount) => amount,
+            None => return OperationStatus::EmptyAmount,
+        };
+        if amount == 0.0 {
+            return OperationStatus::ZeroAmount;
+        }
+        if let Err(error) = source.substract_funds(amount) {
+            return error.into();
+        }
+        if let Err(error) = dest.add_funds(amount) {
+            source
+                .add_funds(amount)
+                .expect("restoring the funds just substracted cannot fail");
+            return error.into();
+        }
+        OperationStatus::Successful(Transaction {
+            client_id: source.client_id(),
+            amount,
+            type_transaction: TransactionType::TransferOut,
+            dispute_state: DisputeState::None,
+            settled: Default::default(),
+        })
+    }
+
+    // Dispatcher function
+    pub fn execute_operation(
+        account: &mut Account,
+        operation: Operation,
+        policy: &Policy,
+    ) -> OperationStatus {
+        // only execute operations if the account is not locked, unless the policy
+        // lets the disputes go on. An unlock is meant for a locked account
+        let dispute_lifecycle = operation.type_operation.references_transaction();
+        let unlock = operation.type_operation == TypeOperation::unlock;
+        if account.locked && !unlock && !(dispute_lifecycle && policy.allow_dispute_when_locked) {
+            return OperationStatus::AccountLocked;
+        }
+        // the dispute lifecycle works with the amount of the referenced transaction, a row
+        // carrying its own amount is malformed. Partial disputes let a resolve or a chargeback
+        // settle only part of the held funds
+        let partial = policy.partial_disputes && operation.type_operation != TypeOperation::dispute;
+        if dispute_lifecycle && operation.amount.is_some() && !partial {
+            return OperationStatus::UnexpectedAmount;
+        }
+        match operation.type_operation {
+            TypeOperation::deposit => Self::execute_deposit(account, operation),
+            TypeOperation::withdrawal => Self::execute_withdrawal(account, operation, policy),
+            TypeOperation::dispute => Self::execute_dispute(account, operation),
+            TypeOperation::resolve => Self::execute_resolve(account, operation),
+            TypeOperation::chargeback => Self::execute_chargeback(account, operation),
+            // a transfer involves two accounts, see execute_transfer
+            TypeOperation::transfer => OperationStatus::InvalidTransfer,
+            TypeOperation::unlock => Self::execute_unlock(account, operation, policy),
+        }
+    }
+}
+
+impl<S: AccountStore> Engine<S> {
+    // The destination account is taken out of the database while both accounts are modified
+    pub(super) fn execute_transfer(&mut self, operation: Operation) -> OperationStatus {
+        let dest_key = match Engine::transfer_dest(&operation) {
+            Some(dest_key) => dest_key,
+            None => return OperationStatus::InvalidTransfer,
+        };
+        let mut dest = match self.database.remove(&dest_key) {
+            Some(dest) => dest,
+            None => return OperationStatus::NonExistingAccount,
+        };
+        let source = self
+            .database
+            .get_mut(&AccountKey::build(
+                operation.client,
+                operation.currency.clone(),
+            ))
+            .expect("the source account exists");
+        let status = Engine::execute_transfer_between(source, &mut dest, operation, self.history);
+        self.database.insert(dest);
+        status
+    }
+}
@@ -1,6 +1,22 @@
 pub mod account;
+pub mod channel;
+pub mod concurrent;
+mod csv_log;
+pub mod engine;
 pub mod parser;
+pub mod pipeline;
 pub mod processor;
+pub mod progress;
 pub mod reader;
+pub mod rejects;
+pub mod replay;
+pub mod self_check;
+pub mod sharded;
+pub mod snapshot;
+pub mod store;
+pub mod summary;
 mod transaction;
 pub mod writer;
+
+pub use engine::Engine;
+pub use pipeline::{run_pipeline, Pipeline};
@@ -1,44 +1,449 @@
-use anyhow::Result;
-use atm::processor::Processor;
+use anyhow::{anyhow, Context, Result};
+use atm::channel;
+use atm::concurrent::ConcurrentProcessor;
+use atm::parser;
+use atm::parser::Args;
+use atm::processor::{Policy, Processor, ProcessorOptions};
+use atm::progress::Progress;
 use atm::reader;
-use atm::reader::Reader;
+use atm::reader::{Reader, Source};
+use atm::rejects::Rejects;
+use atm::replay::Replay;
+use atm::sharded::ShardedProcessor;
+use atm::snapshot;
+use atm::summary::Summary;
 use atm::writer;
-use atm::writer::Writer;
-use tokio::sync::mpsc;
+use atm::writer::{OutputMode, Writer};
+use clap::Parser;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, io};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tracing_subscriber::EnvFilter;
 
+// How a run which did not fail ended
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    // every row was parsed and every operation applied
+    Clean,
+    // some rows were skipped or some operations rejected
+    Partial,
+}
+
+// 0 for a clean run, 2 if it completed with skipped rows or rejected operations and 1 if it failed
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    // clap exits with 2 on a usage error, which is the code of a partial run here
+    let args = match parse_args(std::env::args_os()) {
+        Ok(args) => args,
+        Err(error) => {
+            let _ = error.print();
+            // --help and --version are not errors
+            return if error.use_stderr() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    // quiet by default, the logs go to stderr so they never mix with the reports
+    let filter = if args.verbose {
+        EnvFilter::new("debug")
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"))
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+    match run(args).await {
+        Ok(Outcome::Clean) => ExitCode::SUCCESS,
+        Ok(Outcome::Partial) => ExitCode::from(2),
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The command line as checked by clap and by Args::validate
+fn parse_args<I, T>(command_line: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let args = Args::try_parse_from(command_line)?;
+    args.validate()?;
+    Ok(args)
+}
+
+// Destination of the reports, and whether the CSV header has to be written: not when they are
+// appended to a file which already has some
+fn open_output(args: &Args) -> Result<(Box<dyn io::Write + Send>, bool)> {
+    let path = match &args.output {
+        Some(path) => path,
+        None => return Ok((Box::new(io::stdout()), true)),
+    };
+    let context = || format!("Cannot open the output file {}", path.display());
+    if !args.output_append {
+        return Ok((
+            Box::new(fs::File::create(path).with_context(context)?),
+            true,
+        ));
+    }
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(context)?;
+    let empty = file.metadata().with_context(context)?.len() == 0;
+    Ok((Box::new(file), empty))
+}
+
+async fn run(args: Args) -> Result<Outcome> {
+    // only the input is checked, nothing is processed
+    if args.validate_only {
+        let validation = parser::validate(&args.input_file, &args.parse_options())?;
+        eprint!("{}", validation);
+        if !validation.is_valid() {
+            return Err(anyhow!("The input is not valid"));
+        }
+        return Ok(Outcome::Clean);
+    }
+    let input_file = args.input_file.clone();
+    let parse_options = args.parse_options();
+    let format = args.format;
+    let delimiter = args.delimiter;
+    let flush_each = args.flush_each;
+    let with_currency = args.with_currency;
+    let trim_zeros = args.trim_zeros;
+    let clients = args.clients.clone();
+    let watch_interval = args
+        .watch
+        .then(|| Duration::from_millis(args.watch_interval));
+    let mode = if args.with_history {
+        OutputMode::History
+    } else if args.aggregate {
+        OutputMode::Aggregate
+    } else {
+        OutputMode::Summary
+    };
+    // stdout is the default destination of the writer
+    let (destination, headers) = open_output(&args)?;
     // the reason I chose mpsc over oneshot is that I leave open the possibility of having more senders for potential software extension
-    let (sender_operations, receiver_operations) = mpsc::unbounded_channel::<reader::Command>();
+    // the channels are unbounded unless a capacity is given, in which case the reader is throttled (backpressure)
+    let (sender_operations, receiver_operations) =
+        channel::channel::<reader::Command>(args.channel_capacity);
     // the reason I chose mpsc over oneshot is that I leave open the possibility of having more senders for potential software extension
-    let (sender_results, receiver_results) = mpsc::unbounded_channel::<writer::Command>();
+    let (sender_results, receiver_results) =
+        channel::channel::<writer::Command>(args.channel_capacity);
+
+    let policy = Policy {
+        allow_dispute_when_locked: args.allow_dispute_when_locked,
+        strict: args.strict,
+        overdraft_limit: args.overdraft_limit.unwrap_or_default(),
+        emit_empty_clients: args.emit_empty_clients,
+        partial_disputes: args.partial_disputes,
+        allow_unlock: args.allow_unlock,
+        max_withdrawal_per_client: args.max_withdrawal_per_client,
+    };
+
+    let progress = args.progress.then(|| Arc::new(Progress::default()));
+    let reader_progress = progress.clone();
+
+    // on Ctrl-C the processor stops and the balances computed so far are still written
+    let (request_shutdown, shutdown) = watch::channel(false);
+    let interrupted = shutdown.clone();
+    // when watching, Ctrl-C is the end of the input: the reader stops and closes the connection,
+    // so the processor still applies every row read until then
+    let (shutdown, reader_shutdown) = match watch_interval {
+        Some(_) => (watch::channel(false).1, shutdown),
+        None => (shutdown.clone(), shutdown),
+    };
+    // the same whichever processor runs
+    let options = ProcessorOptions {
+        decimals: args.precision,
+        rounding: args.rounding,
+        policy,
+        history: !args.no_history,
+        history_retention: args.history_retention,
+        channel_capacity: args.channel_capacity,
+        // the accounts of a previous run, if any
+        database: match &args.snapshot_in {
+            Some(path) => snapshot::load(path)?,
+            None => Default::default(),
+        },
+        snapshot: args.snapshot_out.clone(),
+        progress: progress.clone(),
+        rejects: args
+            .rejects_out
+            .as_deref()
+            .map(Rejects::create)
+            .transpose()?,
+        replay: args.replay_out.as_deref().map(Replay::create).transpose()?,
+        shutdown: Some(shutdown),
+    };
+
+    let start_signal = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Interrupted, writing the balances computed so far");
+            let _ = request_shutdown.send(true);
+        }
+    });
 
     // create a task for the main processor
-    let start_processor: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let mut processor = Processor::build(receiver_operations, sender_results);
-        processor.run().await?;
-        Ok(())
+    let start_processor: JoinHandle<Result<Summary>> = tokio::spawn(async move {
+        let workers = args.workers as usize;
+        let summary = if args.shared_accounts {
+            // the workers apply the operations over the same accounts
+            let mut processor =
+                ConcurrentProcessor::build(receiver_operations, sender_results, workers)
+                    .with_options(options);
+            processor.run().await?;
+            processor.summary().clone()
+        } else if workers > 1 {
+            // the processor fans the operations out to several workers
+            let mut processor =
+                ShardedProcessor::build(receiver_operations, sender_results, workers)
+                    .with_options(options);
+            processor.run().await?;
+            processor.summary().clone()
+        } else {
+            let mut processor =
+                Processor::build(receiver_operations, sender_results).with_options(options);
+            if args.self_check {
+                processor = processor.with_self_check();
+            }
+            processor.run().await?;
+            if let Some(reconciliation) = processor.self_check() {
+                eprint!("{}", reconciliation);
+                if !reconciliation.is_consistent() {
+                    return Err(anyhow!("The self-check found inconsistent accounts"));
+                }
+            }
+            processor.summary().clone()
+        };
+        Ok(summary)
     });
 
-    // create a task for the writer (receive results and write them thru the terminal)
+    // create a task for the writer (receive results and write them thru the terminal or the output file)
     let start_writer: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let mut writer = Writer::build(receiver_results);
+        let mut writer = Writer::build(receiver_results, format, destination)
+            .with_mode(mode)
+            .with_delimiter(delimiter)
+            .with_flush_each(flush_each)
+            .with_currency(with_currency)
+            .with_trim_zeros(trim_zeros)
+            .with_headers(headers);
+        if !clients.is_empty() {
+            writer = writer.with_clients(clients.into_iter().collect());
+        }
         writer.run().await?;
         Ok(())
     });
 
     // create a task for the CSV reader
-    let start_reader: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let reader = Reader::build(sender_operations);
-        reader.run().await?;
-        Ok(())
+    let start_reader: JoinHandle<Result<u64>> = tokio::spawn(async move {
+        let mut reader = Reader::build(sender_operations, Source::File(input_file), parse_options)
+            .with_shutdown(reader_shutdown);
+        if let Some(interval) = watch_interval {
+            reader = reader.with_watch(interval);
+        }
+        if let Some(progress) = reader_progress {
+            reader = reader.with_progress(progress);
+        }
+        reader.run().await
     });
 
     // Wait for the tasks to finish and propagate the error if any
-    // This should be done in a better wait: handle the errors properly
-    start_reader.await?.expect("Reader task failed");
-    start_processor.await?.expect("Processor task failed");
-    start_writer.await?.expect("Writer task failed");
-    Ok(())
+    // The processor error is reported first: if it aborts (e.g. --strict), the reader fails
+    // as well because nobody receives its operations anymore
+    let reader_result = start_reader.await?;
+    let mut summary = start_processor.await?.context("Processor task failed")?;
+    start_signal.abort();
+    let interrupted = *interrupted.borrow();
+    match reader_result {
+        Ok(skipped) => summary.skipped = skipped,
+        // nobody receives the rest of the operations once the processor was interrupted
+        Err(_) if interrupted => {}
+        Err(error) => return Err(error.context("Reader task failed")),
+    }
+    start_writer.await?.context("Writer task failed")?;
+    // the summary goes to stderr so it does not mix with the reports
+    if let Some(progress) = progress {
+        eprintln!("{}", progress);
+    }
+    eprint!("{}", summary);
+    // not all the input was processed if it was interrupted, unless it was being watched
+    if summary.is_clean() && (!interrupted || watch_interval.is_some()) {
+        Ok(Outcome::Clean)
+    } else {
+        Ok(Outcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{parse_args, run, Outcome};
+    use atm::parser::Args;
+    use clap::Parser;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+
+    async fn run_input(name: &str, data: &str, options: &[&str]) -> Outcome {
+        let input = env::temp_dir().join(format!("atm_{}_{}.csv", name, std::process::id()));
+        let output = input.with_extension("out");
+        fs::write(&input, data).expect("cannot write the input");
+        let mut args = vec![
+            "atm".as_ref(),
+            input.as_os_str(),
+            "--output".as_ref(),
+            output.as_os_str(),
+        ];
+        args.extend(options.iter().map(OsStr::new));
+        let args = Args::parse_from(args);
+        let outcome = run(args).await.expect("the run failed");
+        fs::remove_file(&input).expect("cannot remove the input");
+        fs::remove_file(&output).expect("cannot remove the output");
+        outcome
+    }
+
+    #[tokio::test]
+    async fn test_outcome() {
+        let clean = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,0.5";
+        assert_eq!(run_input("clean", clean, &[]).await, Outcome::Clean);
+        let skipped = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,x,1.0";
+        assert_eq!(run_input("skipped", skipped, &[]).await, Outcome::Partial);
+        let rejected = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,5.0";
+        assert_eq!(run_input("rejected", rejected, &[]).await, Outcome::Partial);
+    }
+
+    #[tokio::test]
+    async fn test_output_append() {
+        let input = env::temp_dir().join(format!("atm_append_{}.csv", std::process::id()));
+        let output = input.with_extension("out");
+        for (day, data) in [
+            "type,client,tx,amount\ndeposit,1,1,1.0",
+            "type,client,tx,amount\ndeposit,2,1,2.0",
+        ]
+        .iter()
+        .enumerate()
+        {
+            fs::write(&input, data).expect("cannot write the input");
+            let args = Args::parse_from([
+                "atm".as_ref(),
+                input.as_os_str(),
+                "--output".as_ref(),
+                output.as_os_str(),
+                "--output-append".as_ref(),
+            ]);
+            assert_eq!(
+                run(args).await.expect("the run failed"),
+                Outcome::Clean,
+                "{}",
+                day
+            );
+        }
+        let written = fs::read_to_string(&output).expect("cannot read the output");
+        fs::remove_file(&input).expect("cannot remove the input");
+        fs::remove_file(&output).expect("cannot remove the output");
+        assert_eq!(
+            written,
+            "client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,2.0000,0.0000,2.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn test_clients() {
+        for options in [
+            &["atm", "input.csv", "--client", "1", "--client", "7"][..],
+            &["atm", "input.csv", "--clients", "1,7"],
+        ] {
+            assert_eq!(Args::parse_from(options).clients, vec![1, 7]);
+        }
+        assert!(Args::parse_from(["atm", "input.csv"]).clients.is_empty());
+    }
+
+    #[test]
+    fn test_watch_json() {
+        let args = |format: &str| parse_args(["atm", "input.csv", "--watch", "--format", format]);
+        assert!(args("csv").is_ok());
+        assert!(args("json").is_err());
+    }
+
+    #[test]
+    fn test_shared_accounts() {
+        let args = |workers: &str| {
+            parse_args([
+                "atm",
+                "input.csv",
+                "--shared-accounts",
+                "--workers",
+                workers,
+            ])
+        };
+        assert!(args("2").is_ok());
+        assert!(args("1").is_err());
+        assert!(parse_args(["atm", "input.csv", "--shared-accounts"]).is_err());
+    }
+
+    // The ledger is only kept by the single processor
+    #[test]
+    fn test_self_check() {
+        let args = |extra: &[&str]| {
+            let mut command_line = vec!["atm", "input.csv", "--self-check"];
+            command_line.extend(extra);
+            parse_args(command_line)
+        };
+        assert!(args(&[]).is_ok());
+        assert!(args(&["--workers", "1"]).is_ok());
+        assert!(args(&["--workers", "2"]).is_err());
+        assert!(args(&["--workers", "2", "--shared-accounts"]).is_err());
+    }
+
+    // The usage errors are failures (1), --help is not
+    #[test]
+    fn test_usage_error() {
+        let error = parse_args(["atm", "input.csv", "--unknown"]).expect_err("unknown option");
+        assert!(error.use_stderr());
+        let error = parse_args(["atm", "--help"]).expect_err("no input");
+        assert!(!error.use_stderr());
+    }
+
+    #[test]
+    fn test_channel_capacity() {
+        let parse = |capacity: &str| {
+            Args::try_parse_from(["atm", "input.csv", "--channel-capacity", capacity])
+                .map(|args| args.channel_capacity)
+        };
+        assert_eq!(parse("8").ok(), Some(Some(8)));
+        // a bounded channel without room would panic
+        assert!(parse("0").is_err());
+        assert!(parse("-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sort_by_time() {
+        let data = "type,client,tx,amount,timestamp
+deposit,1,1,1.0,10
+dispute,1,2,,30
+deposit,1,2,1.0,20";
+        assert_eq!(run_input("unsorted", data, &[]).await, Outcome::Partial);
+        assert_eq!(
+            run_input("sorted", data, &["--sort-by-time"]).await,
+            Outcome::Clean
+        );
+    }
 }
@@ -1,51 +1,1391 @@
-use anyhow::Result;
-use clap::Parser;
-use serde::Deserialize;
-use std::path::PathBuf;
-use std::{fs, io};
+use crate::account::{Account, Rounding};
+use crate::writer::OutputFormat;
+use anyhow::{anyhow, Context, Result};
+use clap::{CommandFactory, ErrorKind, Parser};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::read::GzDecoder;
+use serde::{de, Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TypeOperation {
     deposit,
     withdrawal,
     dispute,
     resolve,
     chargeback,
+    transfer,
+    // clears the lock of an account, only with --allow-unlock
+    unlock,
 }
 
-#[derive(Debug, Deserialize)]
+// Partner exports are inconsistent about casing and spacing, so the type is trimmed and
+// lowercased before matching it. Unknown types are still malformed rows
+impl<'de> Deserialize<'de> for TypeOperation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let type_operation = String::deserialize(deserializer)?;
+        match type_operation.trim().to_lowercase().as_str() {
+            "deposit" => Ok(Self::deposit),
+            "withdrawal" => Ok(Self::withdrawal),
+            "dispute" => Ok(Self::dispute),
+            "resolve" => Ok(Self::resolve),
+            "chargeback" => Ok(Self::chargeback),
+            "transfer" => Ok(Self::transfer),
+            "unlock" => Ok(Self::unlock),
+            _ => Err(de::Error::unknown_variant(
+                &type_operation,
+                &[
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                    "resolve",
+                    "chargeback",
+                    "transfer",
+                    "unlock",
+                ],
+            )),
+        }
+    }
+}
+
+impl TypeOperation {
+    // Whether the operation creates a new transaction (and therefore a new tx id),
+    // the rest of operations reference an existing one
+    pub fn creates_transaction(&self) -> bool {
+        matches!(self, Self::deposit | Self::withdrawal | Self::transfer)
+    }
+
+    // Whether the operation references the transaction of its tx id (the dispute lifecycle)
+    pub fn references_transaction(&self) -> bool {
+        matches!(self, Self::dispute | Self::resolve | Self::chargeback)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Operation {
     #[serde(rename = "type")]
     pub type_operation: TypeOperation,
     pub client: u16,
     pub tx: u32,
+    // default is needed because a custom deserializer loses the implicit None of a missing Option field
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<f64>,
+    // destination client of a transfer
+    #[serde(default)]
+    pub dest: Option<u16>,
+    // when the operation happened (e.g. seconds since the epoch), only used to sort the input
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    // the client has an account per currency, None (no column or an empty one) for the default
+    #[serde(default)]
+    pub currency: Option<String>,
+    // reference of the upstream (e.g. an idempotency key) the disputes can target instead of the
+    // tx, see Account::references
+    #[serde(default, rename = "ref")]
+    pub reference: Option<String>,
+    // a deposit exported while it was already under dispute, so the resolve or chargeback of
+    // the same file can settle it. Read from a `disputed` or `state` column, ignored by the
+    // other operations
+    #[serde(default, alias = "state", deserialize_with = "deserialize_disputed")]
+    pub disputed: bool,
+}
+
+#[cfg(test)]
+impl Operation {
+    // An operation of the default currency with only the common fields, for the tests
+    pub(crate) fn new(
+        type_operation: TypeOperation,
+        client: u16,
+        tx: u32,
+        amount: Option<f64>,
+    ) -> Self {
+        Self {
+            type_operation,
+            client,
+            tx,
+            amount,
+            dest: None,
+            timestamp: None,
+            currency: None,
+            reference: None,
+            disputed: false,
+        }
+    }
+
+    pub(crate) fn with_dest(mut self, dest: u16) -> Self {
+        self.dest = Some(dest);
+        self
+    }
+}
+
+// Negative, NaN or infinite amounts are malformed input, so the row fails to parse
+// the same way as any other unparseable row
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(amount) if !amount.is_finite() || amount < 0.0 => {
+            Err(de::Error::custom(format!("invalid amount {}", amount)))
+        }
+        amount => Ok(amount),
+    }
+}
+
+// A boolean, or the dispute state the upstream exports. Anything else is a malformed row
+fn deserialize_disputed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    deserializer.deserialize_any(DisputedVisitor)
+}
+
+// The CSV fields which look like a boolean or a number come as such, and so do the JSON values
+struct DisputedVisitor;
+
+impl<'de> de::Visitor<'de> for DisputedVisitor {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a boolean or a dispute state")
+    }
+
+    fn visit_bool<E: de::Error>(self, disputed: bool) -> Result<bool, E> {
+        Ok(disputed)
+    }
+
+    fn visit_u64<E: de::Error>(self, disputed: u64) -> Result<bool, E> {
+        match disputed {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(E::custom(format!("invalid dispute state {}", disputed))),
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, disputed: i64) -> Result<bool, E> {
+        Err(E::custom(format!("invalid dispute state {}", disputed)))
+    }
+
+    fn visit_str<E: de::Error>(self, disputed: &str) -> Result<bool, E> {
+        match disputed.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" | "disputed" => Ok(true),
+            "" | "false" | "0" | "no" | "none" | "resolved" => Ok(false),
+            _ => Err(E::custom(format!("invalid dispute state {}", disputed))),
+        }
+    }
+
+    // a JSON null
+    fn visit_unit<E: de::Error>(self) -> Result<bool, E> {
+        Ok(false)
+    }
+}
+
+// Same validation as the amounts of the input
+fn parse_amount_limit(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(limit) if limit.is_finite() && limit >= 0.0 => Ok(limit),
+        _ => Err(format!("invalid amount {}", value)),
+    }
+}
+
+// A bounded channel needs room for at least one message
+fn parse_channel_capacity(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(capacity) if capacity > 0 => Ok(capacity),
+        _ => Err(format!("invalid capacity {}, it must be at least 1", value)),
+    }
+}
+
+// A single ASCII character, `\t` stands for a tab
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value {
+        "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!("invalid delimiter {}", value)),
+    }
 }
 
 #[derive(Parser, Default, Debug)]
-struct Args {
+pub struct Args {
     // PathBuf must be used instead of String because there exist valid path characters
     // which are not valid String unicode
-    input_file: PathBuf,
+    pub input_file: PathBuf,
+    // If set, the channels between tasks are bounded to this capacity so the reader
+    // is throttled when the processor falls behind. Unbounded by default
+    #[clap(long, value_parser = parse_channel_capacity)]
+    pub channel_capacity: Option<usize>,
+    // Number of worker tasks the accounts are partitioned across (by client id)
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub workers: u16,
+    // Reconcile the accounts with a ledger of the applied operations at the end, the run fails
+    // if they do not match. Only with a single processor, see Args::validate
+    #[clap(long)]
+    pub self_check: bool,
+    // The workers share the accounts in a concurrent map instead of partitioning them, so the
+    // transfers between any clients are applied
+    #[clap(long, conflicts_with = "history-retention")]
+    pub shared_accounts: bool,
+    // Number of decimal places the amounts are kept with (e.g. 2 for cents)
+    #[clap(long, default_value_t = Account::DEFAULT_DECIMALS, value_parser = clap::value_parser!(u32).range(0..=15))]
+    pub precision: u32,
+    // How the amounts with more decimals than --precision are scaled to it
+    #[clap(long, value_enum, default_value_t)]
+    pub rounding: Rounding,
+    // Keep executing disputes, resolves and chargebacks on locked accounts
+    #[clap(long)]
+    pub allow_dispute_when_locked: bool,
+    // Abort the run (with a non-zero exit code) on the first malformed row or rejected operation
+    // instead of ignoring it
+    #[clap(long)]
+    pub strict: bool,
+    // Let the withdrawals leave the available funds negative, down to minus this amount
+    #[clap(long, value_parser = parse_amount_limit)]
+    pub overdraft_limit: Option<f64>,
+    // Reject the withdrawals of a client (in each currency) once they add up to more than this
+    // amount in the run
+    #[clap(long, value_name = "AMOUNT", value_parser = parse_amount_limit)]
+    pub max_withdrawal_per_client: Option<f64>,
+    // Report a zeroed account for the clients whose operations were all rejected
+    #[clap(long)]
+    pub emit_empty_clients: bool,
+    // Let the resolves and chargebacks carry the amount they settle, the rest stays held
+    #[clap(long)]
+    pub partial_disputes: bool,
+    // Accept the unlock operations, which clear the lock of an account. Only for a trusted input
+    #[clap(long)]
+    pub allow_unlock: bool,
+    // Keep at most this number of finalized transactions per account, the oldest are evicted
+    #[clap(long)]
+    pub history_retention: Option<usize>,
+    // Do not keep the transaction histories, only their tx ids: much less memory for a pure
+    // balance computation of a huge input, but every dispute, resolve and chargeback is rejected
+    #[clap(long, conflicts_with_all = &["history-retention", "shared-accounts", "with-history"])]
+    pub no_history: bool,
+    // Log the applied and ignored operations to stderr (RUST_LOG can be used for finer control)
+    #[clap(long)]
+    pub verbose: bool,
+    // Write how many rows were read and operations applied to stderr, about every second
+    #[clap(long)]
+    pub progress: bool,
+    // Format of the client reports
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+    // Write the transaction history of every account instead of its balance
+    #[clap(long)]
+    pub with_history: bool,
+    // Only write the accounts of these clients, e.g. `--client 1 --client 7` or `--clients 1,7`.
+    // The operations of every client are still processed
+    #[clap(
+        long = "client",
+        alias = "clients",
+        value_name = "ID",
+        value_delimiter = ','
+    )]
+    pub clients: Vec<u16>,
+    // Write a single row with the sums of all the accounts instead of a row for each of them
+    #[clap(long, conflicts_with = "with-history")]
+    pub aggregate: bool,
+    // Add a currency column to the reports, empty for the accounts in the default currency
+    #[clap(long)]
+    pub with_currency: bool,
+    // Write the amounts without the trailing zeros of their decimals (10.5 instead of 10.5000)
+    #[clap(long)]
+    pub trim_zeros: bool,
+    // Flush every CSV record as soon as it is written, for a live consumer of the output
+    #[clap(long)]
+    pub flush_each: bool,
+    // File the client reports are written to (truncating it), stdout if not given
+    #[clap(long)]
+    pub output: Option<PathBuf>,
+    // Append the reports to the output file instead of truncating it, without the CSV header if
+    // the file already has content (e.g. the reports of the previous batches)
+    #[clap(long, requires = "output")]
+    pub output_append: bool,
+    // CSV the rejected operations are written to, with the reason of the rejection
+    #[clap(long)]
+    pub rejects_out: Option<PathBuf>,
+    // CSV the applied operations are written to, which can be run again to reproduce the accounts
+    #[clap(long)]
+    pub replay_out: Option<PathBuf>,
+    // File the accounts (with their transaction history) are saved to as JSON at the end
+    #[clap(long)]
+    pub snapshot_out: Option<PathBuf>,
+    // Snapshot (written by --snapshot-out) the accounts are loaded from before processing
+    #[clap(long)]
+    pub snapshot_in: Option<PathBuf>,
+    // Format of the input, CSV with a header or newline-delimited JSON
+    #[clap(long, value_enum, default_value_t)]
+    pub input_format: InputFormat,
+    // Field delimiter of the input and the CSV output, `\t` for tab separated files
+    #[clap(long, default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+    // The input is gzip compressed (implied by a .gz extension)
+    #[clap(long)]
+    pub gzip: bool,
+    // Round the amounts with more decimals than --precision (with a warning) instead of
+    // skipping their rows as malformed
+    #[clap(long)]
+    pub round_amounts: bool,
+    // Accept amounts with a leading currency symbol and thousands separators (e.g. `$1,000.50`)
+    #[clap(long)]
+    pub lenient_amounts: bool,
+    // Thousands separators stripped from the amounts with --lenient-amounts
+    #[clap(long, default_value = ",")]
+    pub grouping: String,
+    // Only parse the input and check it, reporting to stderr. No account is modified nor reported
+    #[clap(long)]
+    pub validate_only: bool,
+    // Names of the input columns, for files whose headers differ from the default ones
+    #[clap(long, default_value = "type")]
+    pub col_type: String,
+    #[clap(long, default_value = "client")]
+    pub col_client: String,
+    #[clap(long, default_value = "tx")]
+    pub col_tx: String,
+    #[clap(long, default_value = "amount")]
+    pub col_amount: String,
+    #[clap(long, default_value = "dest")]
+    pub col_dest: String,
+    #[clap(long, default_value = "timestamp")]
+    pub col_timestamp: String,
+    #[clap(long, default_value = "currency")]
+    pub col_currency: String,
+    // Sort the operations by their timestamp before processing them (the ties and the
+    // operations without timestamp keep their input order)
+    #[clap(long)]
+    pub sort_by_time: bool,
+    // Stop reading the input after this many operations, e.g. to try a sample of a huge file
+    #[clap(long)]
+    pub max_rows: Option<u64>,
+    // Keep the input open and apply the rows appended to it until Ctrl-C, reporting the accounts
+    // they touch as soon as they are applied (e.g. for a live dashboard)
+    #[clap(long, conflicts_with_all = &["gzip", "sort-by-time", "max-rows", "validate-only"])]
+    pub watch: bool,
+    // Milliseconds between two reads of the input with --watch
+    #[clap(long, default_value_t = 500)]
+    pub watch_interval: u64,
+}
+
+impl Args {
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            format: self.input_format,
+            columns: Columns {
+                type_operation: self.col_type.clone(),
+                client: self.col_client.clone(),
+                tx: self.col_tx.clone(),
+                amount: self.col_amount.clone(),
+                dest: self.col_dest.clone(),
+                timestamp: self.col_timestamp.clone(),
+                currency: self.col_currency.clone(),
+            },
+            strict: self.strict,
+            gzip: self.gzip,
+            delimiter: self.delimiter,
+            decimals: self.precision,
+            round_amounts: self.round_amounts,
+            sort_by_time: self.sort_by_time,
+            lenient_amounts: self.lenient_amounts,
+            grouping: self.grouping.clone(),
+            max_rows: self.max_rows,
+        }
+    }
+
+    // The combinations of options which depend on their values, clap only checks their presence
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        // a single processor has nothing to share, the option would be ignored
+        if self.shared_accounts && self.workers < 2 {
+            return Err(Self::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "--shared-accounts requires --workers greater than 1",
+            ));
+        }
+        // only the single processor keeps the ledger, --workers 1 is the same one
+        if self.self_check && (self.workers > 1 || self.shared_accounts) {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "--self-check cannot be used with more than one worker or --shared-accounts",
+            ));
+        }
+        // a JSON array is only complete at the end, the running reports could not be written
+        if self.watch && self.format == OutputFormat::Json {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "--watch cannot be used with --format json",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Format of the input
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    // newline-delimited JSON, an object per operation
+    Ndjson,
+}
+
+// Names of the input columns of every Operation field
+#[derive(Debug, Clone, PartialEq)]
+pub struct Columns {
+    pub type_operation: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+    pub dest: String,
+    pub timestamp: String,
+    pub currency: String,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self {
+            type_operation: "type".to_string(),
+            client: "client".to_string(),
+            tx: "tx".to_string(),
+            amount: "amount".to_string(),
+            dest: "dest".to_string(),
+            timestamp: "timestamp".to_string(),
+            currency: "currency".to_string(),
+        }
+    }
+}
+
+impl Columns {
+    // Translate an input header into the name the Operation field is deserialized from
+    fn canonical<'a>(&self, header: &'a str) -> &'a str {
+        if header == self.type_operation {
+            "type"
+        } else if header == self.client {
+            "client"
+        } else if header == self.tx {
+            "tx"
+        } else if header == self.amount {
+            "amount"
+        } else if header == self.dest {
+            "dest"
+        } else if header == self.timestamp {
+            "timestamp"
+        } else if header == self.currency {
+            "currency"
+        } else {
+            header
+        }
+    }
+
+    // Fail if any of the required columns is not in the input headers. An empty input has no
+    // headers at all and is accepted
+    fn check(&self, headers: &csv::StringRecord) -> Result<()> {
+        if headers.is_empty() {
+            return Ok(());
+        }
+        let missing: Vec<&str> = [&self.type_operation, &self.client, &self.tx, &self.amount]
+            .iter()
+            .filter(|column| !headers.iter().any(|header| header == column.as_str()))
+            .map(|column| column.as_str())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Missing columns {} in the header \"{}\"",
+                missing.join(","),
+                headers.iter().collect::<Vec<_>>().join(",")
+            ))
+        }
+    }
 }
 
-// Parse the CSV into a vector of Operation
-pub fn parse() -> Result<Vec<Operation>> {
-    let args = Args::parse();
-    let file_reader = fs::File::open(args.input_file)?;
+// How the input is parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseOptions {
+    pub format: InputFormat,
+    pub columns: Columns,
+    // fail on the first malformed row instead of skipping it
+    pub strict: bool,
+    // decompress the input, even if its extension is not .gz
+    pub gzip: bool,
+    pub delimiter: u8,
+    // maximum number of decimals of the amounts
+    pub decimals: u32,
+    // round the amounts with more decimals instead of failing
+    pub round_amounts: bool,
+    // sort the operations by timestamp
+    pub sort_by_time: bool,
+    // strip a currency symbol and the thousands separators of the amounts before parsing them
+    pub lenient_amounts: bool,
+    // characters which separate the thousands
+    pub grouping: String,
+    // number of operations after which the rest of the input is not read
+    pub max_rows: Option<u64>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            format: Default::default(),
+            columns: Default::default(),
+            strict: false,
+            gzip: false,
+            delimiter: b',',
+            decimals: Account::DEFAULT_DECIMALS,
+            round_amounts: false,
+            sort_by_time: false,
+            lenient_amounts: false,
+            grouping: ",".to_string(),
+            max_rows: None,
+        }
+    }
+}
+
+// Whether the input has to be decompressed
+pub(crate) fn is_gzip(input_file: &Path, options: &ParseOptions) -> bool {
+    options.gzip
+        || input_file
+            .extension()
+            .is_some_and(|extension| extension == "gz")
+}
+
+// Open the input, decompressing it if needed
+fn open(input_file: &Path, options: &ParseOptions) -> Result<Box<dyn io::Read>> {
+    let file = fs::File::open(input_file)
+        .with_context(|| format!("Cannot open the input file {}", input_file.display()))?;
+    let file_reader = io::BufReader::new(file);
+    if is_gzip(input_file, options) {
+        Ok(Box::new(GzDecoder::new(file_reader)))
+    } else {
+        Ok(Box::new(file_reader))
+    }
+}
+
+// Parse the CSV into a vector of Operation, along with the number of skipped rows
+pub fn parse(input_file: &Path, options: &ParseOptions) -> Result<(Vec<Operation>, u64)> {
+    parse_rows(open(input_file, options)?, options)
+        .with_context(|| format!("Cannot parse the input file {}", input_file.display()))
+}
 
+// Parse CSV (or NDJSON) data from any source into a vector of Operation, along with the number
+// of rows which could not be parsed (and were skipped)
+pub fn parse_rows<R: io::Read>(reader: R, options: &ParseOptions) -> Result<(Vec<Operation>, u64)> {
+    // Excel exports start with a BOM, which would end up in the first header. A UTF-16 BOM
+    // also makes the input be transcoded to UTF-8, without BOM it is read as it is
+    let reader = DecodeReaderBytesBuilder::new()
+        .strip_bom(true)
+        .build(reader);
+    let (mut list_operations, skipped) = match options.format {
+        InputFormat::Csv => parse_csv(reader, options)?,
+        InputFormat::Ndjson => parse_ndjson(reader, options)?,
+    };
+    // the whole input must be buffered to sort it
+    if options.sort_by_time {
+        sort_by_time(&mut list_operations);
+    }
+    Ok((list_operations, skipped))
+}
+
+// Only the operations with a timestamp are sorted, among the positions they take: the ones
+// without timestamp stay where they are. The sort is stable, so the ties keep their input order
+fn sort_by_time(list_operations: &mut [Operation]) {
+    let positions: Vec<usize> = (0..list_operations.len())
+        .filter(|&position| list_operations[position].timestamp.is_some())
+        .collect();
+    let mut timed: Vec<Operation> = positions
+        .iter()
+        .map(|&position| list_operations[position].clone())
+        .collect();
+    timed.sort_by_key(|operation| operation.timestamp);
+    for (position, operation) in positions.into_iter().zip(timed) {
+        list_operations[position] = operation;
+    }
+}
+
+fn parse_csv<R: io::Read>(reader: R, options: &ParseOptions) -> Result<(Vec<Operation>, u64)> {
     let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
         .flexible(true) // in case it is not a consistent file
         .trim(csv::Trim::All)
-        .from_reader(io::BufReader::new(file_reader));
+        .from_reader(reader);
+
+    // the headers are translated before deserializing, so the Operation always sees the default names
+    let headers: csv::StringRecord = match rdr.headers() {
+        Ok(headers) => {
+            // a file without header or with other columns would only have malformed rows
+            options.columns.check(headers)?;
+            headers
+                .iter()
+                .map(|header| options.columns.canonical(header))
+                .collect()
+        }
+        Err(error) if options.strict => return Err(error).context("Cannot read the headers"),
+        Err(_) => return Ok((vec![], 0)),
+    };
 
     let mut list_operations = vec![];
-    for result in rdr.deserialize::<Operation>() {
-        match result {
+    let mut skipped = 0;
+    let amount_index = headers.iter().position(|header| header == "amount");
+    for result in rdr.records() {
+        if options
+            .max_rows
+            .is_some_and(|max_rows| list_operations.len() as u64 >= max_rows)
+        {
+            break;
+        }
+        // the raw row is kept to report it
+        let parsed = result.map_err(anyhow::Error::from).and_then(|record| {
+            clean_record(&record, amount_index, options)
+                .and_then(|cleaned| {
+                    let operation = cleaned.deserialize::<Operation>(Some(&headers))?;
+                    check_decimals(amount_index.and_then(|index| cleaned.get(index)), options)?;
+                    Ok(operation)
+                })
+                .map_err(|error| {
+                    let row = format!(
+                        "row \"{}\" at line {}",
+                        record.iter().collect::<Vec<_>>().join(","),
+                        record.position().map_or(0, |position| position.line())
+                    );
+                    match out_of_range_field(&error, &headers) {
+                        Some(field) => {
+                            error.context(format!("Out-of-range field {} in {}", field, row))
+                        }
+                        None => error.context(format!("Malformed {}", row)),
+                    }
+                })
+        });
+        match parsed {
             Ok(v) => list_operations.push(v),
-            Err(_) => continue, // if one line cannot be parsed, ignore it
+            Err(error) if options.strict => return Err(error),
+            // if one line cannot be parsed, ignore it
+            Err(error) => {
+                tracing::warn!("{}, skipped", error);
+                skipped += 1;
+            }
         }
     }
-    Ok(list_operations)
+    Ok((list_operations, skipped))
+}
+
+// One JSON object per line, with the same names as the CSV columns as keys (e.g.
+// `{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}`). The blank lines are ignored
+fn parse_ndjson<R: io::Read>(reader: R, options: &ParseOptions) -> Result<(Vec<Operation>, u64)> {
+    let mut list_operations = vec![];
+    let mut skipped = 0;
+    for (index, line) in io::BufReader::new(reader).lines().enumerate() {
+        if options
+            .max_rows
+            .is_some_and(|max_rows| list_operations.len() as u64 >= max_rows)
+        {
+            break;
+        }
+        let line = line.with_context(|| format!("Cannot read the line {}", index + 1))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = parse_json(&line, options)
+            .with_context(|| format!("Malformed row \"{}\" at line {}", line, index + 1));
+        match parsed {
+            Ok(v) => list_operations.push(v),
+            Err(error) if options.strict => return Err(error),
+            // same as a malformed CSV row
+            Err(error) => {
+                tracing::warn!("{}, skipped", error);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((list_operations, skipped))
+}
+
+// The keys are translated like the CSV headers
+fn parse_json(line: &str, options: &ParseOptions) -> Result<Operation> {
+    let value = match serde_json::from_str(line)? {
+        serde_json::Value::Object(fields) => serde_json::Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (options.columns.canonical(&key).to_string(), value))
+                .collect(),
+        ),
+        value => value,
+    };
+    // the amount as written, for its decimals
+    let amount = value
+        .get("amount")
+        .filter(|amount| amount.is_number())
+        .map(|amount| amount.to_string());
+    let operation = Operation::deserialize(value)?;
+    check_decimals(amount.as_deref(), options)?;
+    Ok(operation)
+}
+
+// Integer field (client, tx, dest or timestamp) which does not fit its type, e.g. a client
+// above 65535 or a tx which is not a number. It is a data quality issue rather than a
+// structural one (e.g. a missing field), so it is reported on its own
+fn out_of_range_field<'a>(
+    error: &anyhow::Error,
+    headers: &'a csv::StringRecord,
+) -> Option<&'a str> {
+    match error.downcast_ref::<csv::Error>()?.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => match err.kind() {
+            csv::DeserializeErrorKind::ParseInt(_) => headers.get(err.field()? as usize),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Currency symbols which can precede a lenient amount
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥'];
+
+// The record with its amount cleaned, only copied with --lenient-amounts
+fn clean_record<'a>(
+    record: &'a csv::StringRecord,
+    amount_index: Option<usize>,
+    options: &ParseOptions,
+) -> Result<Cow<'a, csv::StringRecord>> {
+    let index = match amount_index {
+        Some(index) if options.lenient_amounts && index < record.len() => index,
+        _ => return Ok(Cow::Borrowed(record)),
+    };
+    let amount = clean_amount(&record[index], &options.grouping)?;
+    let mut cleaned: csv::StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(position, field)| if position == index { &amount } else { field })
+        .collect();
+    cleaned.set_position(record.position().cloned());
+    Ok(Cow::Owned(cleaned))
+}
+
+// Plain number of an amount like `$1,000.50`: an optional leading currency symbol and the
+// integer part grouped by thousands. The result is still parsed as any other amount, so a
+// second decimal point fails there
+fn clean_amount(amount: &str, grouping: &str) -> Result<String> {
+    let number = amount.strip_prefix(CURRENCY_SYMBOLS).unwrap_or(amount);
+    let is_separator = |c: char| grouping.contains(c);
+    let (integer, decimals) = match number.split_once('.') {
+        Some((integer, decimals)) => (integer, Some(decimals)),
+        None => (number, None),
+    };
+    let groups: Vec<&str> = integer.split(is_separator).collect();
+    // 1,000 or 10,000,000 but not 1,00,0 nor ,100
+    let well_grouped = groups.len() == 1
+        || ((1..=3).contains(&groups[0].len()) && groups[1..].iter().all(|group| group.len() == 3));
+    if !well_grouped || decimals.is_some_and(|decimals| decimals.contains(is_separator)) {
+        return Err(anyhow!("invalid amount {}", amount));
+    }
+    let mut cleaned = groups.concat();
+    if let Some(decimals) = decimals {
+        cleaned.push('.');
+        cleaned.push_str(decimals);
+    }
+    Ok(cleaned)
+}
+
+// The amount is checked as written in the input, once it is an f64 the extra decimals
+// would be silently rounded by the account. Its decimals cannot be counted in exponent
+// notation (e.g. 0.5e-4), so it is malformed even with round_amounts
+fn check_decimals(amount: Option<&str>, options: &ParseOptions) -> Result<()> {
+    if amount.is_some_and(|amount| amount.contains(['e', 'E'])) {
+        return Err(anyhow!("the amount is in exponent notation"));
+    }
+    let decimals = match amount.and_then(|amount| amount.split_once('.')) {
+        Some((_, decimals)) => decimals.trim_end_matches('0').len() as u32,
+        None => return Ok(()),
+    };
+    if decimals <= options.decimals {
+        return Ok(());
+    }
+    if options.round_amounts {
+        tracing::warn!(amount, "amount rounded to {} decimals", options.decimals);
+        return Ok(());
+    }
+    Err(anyhow!(
+        "the amount has more than {} decimals",
+        options.decimals
+    ))
+}
+
+// Result of checking an input without processing it
+#[derive(Debug, Default, PartialEq)]
+pub struct Validation {
+    pub parsed: u64,
+    pub skipped: u64,
+    // disputes, resolves and chargebacks (client, tx) referencing a tx the client did not
+    // create before
+    pub dangling: Vec<(u16, u32)>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.skipped == 0 && self.dangling.is_empty()
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "parsed rows: {}", self.parsed)?;
+        writeln!(f, "skipped rows: {}", self.skipped)?;
+        writeln!(f, "dangling references: {}", self.dangling.len())?;
+        for (client, tx) in &self.dangling {
+            writeln!(f, "  client {} references tx {}", client, tx)?;
+        }
+        Ok(())
+    }
+}
+
+// Parse the whole input and check the references of the disputes, without touching any account
+pub fn validate(input_file: &Path, options: &ParseOptions) -> Result<Validation> {
+    validate_reader(open(input_file, options)?, options)
+        .with_context(|| format!("Cannot validate the input file {}", input_file.display()))
+}
+
+fn validate_reader<R: io::Read>(reader: R, options: &ParseOptions) -> Result<Validation> {
+    let (operations, skipped) = parse_rows(reader, options)?;
+    // tx: client which created it
+    let mut created = HashMap::new();
+    // (client, reference) of the created transactions
+    let mut references = HashSet::new();
+    let mut dangling = vec![];
+    for operation in &operations {
+        let reference = operation
+            .reference
+            .as_ref()
+            .map(|reference| (operation.client, reference));
+        if operation.type_operation.creates_transaction() {
+            created.entry(operation.tx).or_insert(operation.client);
+            references.extend(reference);
+        } else if operation.type_operation.references_transaction()
+            && created.get(&operation.tx) != Some(&operation.client)
+            && !reference.is_some_and(|reference| references.contains(&reference))
+        {
+            dangling.push((operation.client, operation.tx));
+        }
+    }
+    Ok(Validation {
+        parsed: operations.len() as u64,
+        skipped,
+        dangling,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::{
+        parse, parse_rows, validate_reader, Columns, InputFormat, Operation, ParseOptions,
+        TypeOperation, Validation,
+    };
+    use anyhow::Result;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+
+    // Only the parsed operations
+    fn parse_reader<R: io::Read>(reader: R, options: &ParseOptions) -> Result<Vec<Operation>> {
+        Ok(parse_rows(reader, options)?.0)
+    }
+
+    #[test]
+    fn test_invalid_amounts() {
+        let data = "type, client, tx, amount
+deposit, 1, 1, -5.0
+deposit, 1, 2, nan
+deposit, 1, 3, 2.5
+withdrawal, 1, 4, inf
+dispute, 1, 3,
+resolve, 1, 3";
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(operations.len(), 3);
+        assert_eq!(operations[0].type_operation, TypeOperation::deposit);
+        assert_eq!(operations[0].tx, 3);
+        assert_eq!(operations[0].amount, Some(2.5));
+        assert_eq!(operations[1].type_operation, TypeOperation::dispute);
+        assert_eq!(operations[1].amount, None);
+        assert_eq!(operations[2].type_operation, TypeOperation::resolve);
+        assert_eq!(operations[2].amount, None);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let data = "type,client,tx,amount,dest
+deposit,1,1,1.0
+transfer,1,2,0.5,2
+transfer,1,3,0.5,";
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(operations.len(), 3);
+        assert_eq!(operations[0].dest, None);
+        assert_eq!(operations[1].type_operation, TypeOperation::transfer);
+        assert_eq!(operations[1].dest, Some(2));
+        assert_eq!(operations[2].dest, None);
+    }
+
+    #[test]
+    fn test_type_casing() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+Deposit,1,2,1.0
+DEPOSIT,1,3,1.0
+ WithDrawal ,1,4,1.0
+Dispute,1,1,
+RESOLVE,1,1,
+chargeBack,1,1,
+Unlock,1,0,
+foobar,1,5,1.0
+deposits,1,6,1.0";
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        let types: Vec<TypeOperation> = operations
+            .iter()
+            .map(|operation| operation.type_operation)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TypeOperation::deposit,
+                TypeOperation::deposit,
+                TypeOperation::deposit,
+                TypeOperation::withdrawal,
+                TypeOperation::dispute,
+                TypeOperation::resolve,
+                TypeOperation::chargeback,
+                TypeOperation::unlock,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let csv = "type,client,tx,amount,dest,currency,ref,disputed
+deposit,1,1,10.5,,,abc,
+Deposit,2,2,1.0,,EUR,,true
+withdrawal,1,3,0.25,,,,
+transfer,1,4,1.0,2,,,
+dispute,1,0,,,,abc,
+resolve,2,2,,,EUR,,
+deposit,1,x,1.0,,,,";
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5, "ref": "abc"}
+{"type": "Deposit", "client": 2, "tx": 2, "amount": 1.0, "currency": "EUR", "disputed": true}
+
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.25}
+{"type": "transfer", "client": 1, "tx": 4, "amount": 1.0, "dest": 2}
+{"type": "dispute", "client": 1, "tx": 0, "amount": null, "ref": "abc"}
+{"type": "resolve", "client": 2, "tx": 2, "currency": "EUR"}
+{"type": "deposit", "client": 1, "tx": "x", "amount": 1.0}
+{"type": "deposit", "client": 1, "tx": 5, "amount": 1.00001}
+not json"#;
+        let expected = parse_rows(csv.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(expected.0.len(), 6);
+        let options = ParseOptions {
+            format: InputFormat::Ndjson,
+            ..Default::default()
+        };
+        // the malformed lines are skipped like the rows, the amount with too many decimals too
+        let (operations, skipped) = parse_rows(ndjson.as_bytes(), &options).expect("cannot parse");
+        assert_eq!(operations, expected.0);
+        assert_eq!(skipped, 3);
+
+        let strict = ParseOptions {
+            strict: true,
+            ..options
+        };
+        let error = parse_rows(ndjson.as_bytes(), &strict).expect_err("a malformed line");
+        assert!(error.to_string().contains("at line 8"), "{}", error);
+    }
+
+    #[test]
+    fn test_disputed_column() {
+        let data = "type,client,tx,amount,state
+deposit,1,1,1.0,Disputed
+deposit,1,2,1.0,resolved
+deposit,1,3,1.0,
+deposit,1,4,1.0, TRUE
+deposit,1,5,1.0,maybe";
+        let (operations, skipped) =
+            parse_rows(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(skipped, 1);
+        let disputed: Vec<bool> = operations
+            .iter()
+            .map(|operation| operation.disputed)
+            .collect();
+        assert_eq!(disputed, vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn test_columns() {
+        let data = "kind,client_id,transaction_id,value,note
+deposit,1,1,1.5,first
+withdrawal,1,2,0.5,
+dispute,1,1,,";
+        let options = ParseOptions {
+            columns: Columns {
+                type_operation: "kind".to_string(),
+                client: "client_id".to_string(),
+                tx: "transaction_id".to_string(),
+                amount: "value".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        assert_eq!(operations.len(), 3);
+        assert_eq!(operations[0].type_operation, TypeOperation::deposit);
+        assert_eq!(operations[0].client, 1);
+        assert_eq!(operations[0].tx, 1);
+        assert_eq!(operations[0].amount, Some(1.5));
+        assert_eq!(operations[1].type_operation, TypeOperation::withdrawal);
+        assert_eq!(operations[2].amount, None);
+
+        // the default columns are not found anymore
+        let error = parse_reader(data.as_bytes(), &Default::default())
+            .expect_err("the default columns are missing");
+        assert_eq!(
+            error.to_string(),
+            "Missing columns type,client,tx,amount in the header \"kind,client_id,transaction_id,value,note\""
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        // without header the first row is taken as the header
+        let headerless = "deposit,1,1,1.0
+deposit,1,2,2.0";
+        let error = parse_reader(headerless.as_bytes(), &Default::default())
+            .expect_err("a file without header was accepted");
+        assert_eq!(
+            error.to_string(),
+            "Missing columns type,client,tx,amount in the header \"deposit,1,1,1.0\""
+        );
+
+        let without_tx = "type,client,amount
+deposit,1,1.0";
+        let error = parse_reader(without_tx.as_bytes(), &Default::default())
+            .expect_err("a file without tx column was accepted");
+        assert_eq!(
+            error.to_string(),
+            "Missing columns tx in the header \"type,client,amount\""
+        );
+
+        // the optional columns can be missing, in any order, and an empty input is still valid
+        let reordered = "amount,tx,type,client
+1.0,1,deposit,1";
+        assert_eq!(
+            parse_reader(reordered.as_bytes(), &Default::default())
+                .expect("cannot parse")
+                .len(),
+            1
+        );
+        assert!(parse_reader("".as_bytes(), &Default::default())
+            .expect("cannot parse")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_strict() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,x,2.0
+deposit,1,3,3.0";
+        assert_eq!(
+            parse_reader(data.as_bytes(), &Default::default())
+                .expect("cannot parse")
+                .len(),
+            2
+        );
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let error = parse_reader(data.as_bytes(), &options).expect_err("malformed row accepted");
+        assert_eq!(
+            error.to_string(),
+            "Out-of-range field tx in row \"deposit,1,x,2.0\" at line 3"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,70000,2,2.0
+unknown,1,3,3.0
+deposit,1,4,4.0";
+        let (operations, skipped) =
+            parse_rows(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(operations.len(), 2);
+        assert_eq!(skipped, 2);
+
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let error = parse_reader(data.as_bytes(), &options).expect_err("client out of range");
+        assert_eq!(
+            error.to_string(),
+            "Out-of-range field client in row \"deposit,70000,2,2.0\" at line 3"
+        );
+        // the other errors are still structural
+        let data = "type,client,tx,amount
+unknown,1,3,3.0";
+        let error = parse_reader(data.as_bytes(), &options).expect_err("unknown type");
+        assert_eq!(
+            error.to_string(),
+            "Malformed row \"unknown,1,3,3.0\" at line 2"
+        );
+    }
+
+    #[test]
+    fn test_gzip() {
+        let data = "type,client,tx,amount,dest
+deposit,1,1,1.5
+withdrawal,1,2,0.5
+transfer,1,3,0.25,2
+dispute,1,1,";
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        assert!(encoder.write_all(data.as_bytes()).is_ok());
+        let compressed = encoder.finish().expect("cannot compress");
+        let path = std::env::temp_dir().join(format!("atm_input_{}.csv.gz", std::process::id()));
+        assert!(fs::write(&path, compressed).is_ok());
+
+        let (operations, skipped) = parse(&path, &Default::default()).expect("cannot parse");
+        fs::remove_file(&path).expect("cannot remove the input");
+        let expected = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(operations.len(), 4);
+        assert_eq!(skipped, 0);
+        assert_eq!(operations, expected);
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let path = std::env::temp_dir().join("atm_missing_input.csv");
+        let error = parse(&path, &Default::default()).expect_err("the input does not exist");
+        // the path is in the message, not only in the chain of causes
+        assert_eq!(
+            error.to_string(),
+            format!("Cannot open the input file {}", path.display())
+        );
+        assert!(format!("{:#}", error).contains("No such file or directory"));
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let data = "type,client,tx,amount,dest
+deposit,1,1,1.5
+transfer,1,2,0.5,2
+dispute,1,1,";
+        let expected = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(expected.len(), 3);
+        for delimiter in [b';', b'\t'] {
+            let data = data.replace(',', &(delimiter as char).to_string());
+            let options = ParseOptions {
+                delimiter,
+                ..Default::default()
+            };
+            let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+            assert_eq!(operations, expected);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+dispute,1,1,
+dispute,1,2,
+resolve,2,7,
+deposit,1,x,1.0";
+        let validation =
+            validate_reader(data.as_bytes(), &Default::default()).expect("cannot validate");
+        assert_eq!(
+            validation,
+            Validation {
+                parsed: 5,
+                skipped: 1,
+                dangling: vec![(1, 2), (2, 7)],
+            }
+        );
+        assert!(!validation.is_valid());
+
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,1,";
+        assert!(validate_reader(data.as_bytes(), &Default::default())
+            .expect("cannot validate")
+            .is_valid());
+    }
+
+    #[test]
+    fn test_decimals() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0001
+deposit,1,2,1.00005
+deposit,1,3,1.000100
+deposit,1,4,0.5e-4
+dispute,1,1,";
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        let txs: Vec<u32> = operations.iter().map(|operation| operation.tx).collect();
+        assert_eq!(txs, vec![1, 3, 1]);
+        assert_eq!(operations[0].amount, Some(1.0001));
+
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let error = parse_reader(data.as_bytes(), &options).expect_err("extra decimals accepted");
+        assert_eq!(
+            error.to_string(),
+            "Malformed row \"deposit,1,2,1.00005\" at line 3"
+        );
+
+        // rounded later by the account
+        let options = ParseOptions {
+            round_amounts: true,
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        assert_eq!(operations.len(), 4);
+        assert_eq!(operations[1].amount, Some(1.00005));
+
+        // the precision is configurable
+        let options = ParseOptions {
+            decimals: 5,
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        assert_eq!(operations.len(), 4);
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let data = "type,client,tx,amount
+deposit,1,1,\"1,000.50\"
+deposit,1,2,$1000.50
+deposit,1,3,\"1,00,0.5\"
+deposit,1,4,\"$12,345,678\"
+deposit,1,5,\"1.000.5\"
+withdrawal,1,6,2.5
+dispute,1,1,";
+        // only the plain amounts by default
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        let txs: Vec<u32> = operations.iter().map(|operation| operation.tx).collect();
+        assert_eq!(txs, vec![6, 1]);
+
+        let options = ParseOptions {
+            lenient_amounts: true,
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        let amounts: Vec<(u32, Option<f64>)> = operations
+            .iter()
+            .map(|operation| (operation.tx, operation.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (1, Some(1000.5)),
+                (2, Some(1000.5)),
+                (4, Some(12345678.0)),
+                (6, Some(2.5)),
+                (1, None)
+            ]
+        );
+
+        let options = ParseOptions {
+            strict: true,
+            ..options
+        };
+        let error = parse_reader(data.as_bytes(), &options).expect_err("malformed grouping");
+        assert_eq!(
+            error.to_string(),
+            "Malformed row \"deposit,1,3,1,00,0.5\" at line 4"
+        );
+
+        // other separators
+        let data = "type,client,tx,amount
+deposit,1,1,1_000.5
+deposit,1,2,1 000.5";
+        let options = ParseOptions {
+            lenient_amounts: true,
+            grouping: "_ ".to_string(),
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        assert_eq!(operations.len(), 2);
+        assert!(operations
+            .iter()
+            .all(|operation| operation.amount == Some(1000.5)));
+    }
+
+    #[test]
+    fn test_sort_by_time() {
+        let data = "type,client,tx,amount,dest,timestamp
+dispute,1,1,,,20
+deposit,1,2,2.0,,10
+deposit,1,1,1.0,,10
+withdrawal,1,3,0.5,,30";
+        let options = ParseOptions {
+            sort_by_time: true,
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        let order: Vec<(TypeOperation, u32)> = operations
+            .iter()
+            .map(|operation| (operation.type_operation, operation.tx))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (TypeOperation::deposit, 2),
+                (TypeOperation::deposit, 1),
+                (TypeOperation::dispute, 1),
+                (TypeOperation::withdrawal, 3),
+            ]
+        );
+        // the dispute now references an existing deposit
+        assert!(validate_reader(data.as_bytes(), &options)
+            .expect("cannot validate")
+            .is_valid());
+
+        // input order by default
+        let operations = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(operations[0].type_operation, TypeOperation::dispute);
+        assert_eq!(operations[0].timestamp, Some(20));
+    }
+
+    // The operations without timestamp keep their input position
+    #[test]
+    fn test_sort_by_time_partial() {
+        let data = "type,client,tx,amount,dest,timestamp
+deposit,1,1,1.0,,30
+deposit,1,2,1.0,,
+deposit,1,3,1.0,,10
+deposit,1,4,1.0,,
+deposit,1,5,1.0,,20";
+        let options = ParseOptions {
+            sort_by_time: true,
+            ..Default::default()
+        };
+        let operations = parse_reader(data.as_bytes(), &options).expect("cannot parse");
+        let order: Vec<u32> = operations.iter().map(|operation| operation.tx).collect();
+        assert_eq!(order, vec![3, 2, 5, 4, 1]);
+    }
+
+    #[test]
+    fn test_bom() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,0.5";
+        let expected = parse_reader(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(expected.len(), 2);
+
+        let utf8 = [&b"\xef\xbb\xbf"[..], data.as_bytes()].concat();
+        let operations = parse_reader(&utf8[..], &Default::default()).expect("cannot parse");
+        assert_eq!(operations, expected);
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend(data.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let operations = parse_reader(&utf16[..], &Default::default()).expect("cannot parse");
+        assert_eq!(operations, expected);
+    }
 }
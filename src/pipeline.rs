@@ -0,0 +1,302 @@
+use crate::account::Rounding;
+use crate::channel;
+use crate::parser::ParseOptions;
+use crate::processor::{Policy, Processor};
+use crate::reader::{Reader, Source};
+use crate::sharded::ShardedProcessor;
+use crate::summary::Summary;
+use crate::writer::{OutputFormat, OutputMode, Writer};
+use crate::{reader, writer};
+use anyhow::{Context, Result};
+use std::io;
+
+// The whole reader -> processor -> writer pipeline over any input and output, without files
+// nor process arguments (e.g. for integration tests or to embed it)
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    // the precision of the accounts is the one of the parser
+    parse_options: ParseOptions,
+    rounding: Rounding,
+    policy: Policy,
+    workers: usize,
+    channel_capacity: Option<usize>,
+    format: OutputFormat,
+    mode: OutputMode,
+    with_currency: bool,
+    trim_zeros: bool,
+}
+
+impl Pipeline {
+    pub fn with_parse_options(mut self, parse_options: ParseOptions) -> Self {
+        self.parse_options = parse_options;
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // More than one worker uses the sharded processor
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn with_channel_capacity(mut self, channel_capacity: Option<usize>) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: OutputMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_currency(mut self, with_currency: bool) -> Self {
+        self.with_currency = with_currency;
+        self
+    }
+
+    pub fn with_trim_zeros(mut self, trim_zeros: bool) -> Self {
+        self.trim_zeros = trim_zeros;
+        self
+    }
+
+    // Read the CSV operations from the input and write the reports to the output. The three
+    // stages run concurrently in the current task, so the output does not need to be 'static
+    pub async fn run<R, W>(&self, input: R, output: W) -> Result<Summary>
+    where
+        R: io::Read + Send + 'static,
+        W: io::Write,
+    {
+        let (sender_operations, receiver_operations) =
+            channel::channel::<reader::Command>(self.channel_capacity);
+        let (sender_results, receiver_results) =
+            channel::channel::<writer::Command>(self.channel_capacity);
+        let decimals = self.parse_options.decimals;
+
+        let reader = Reader::build(
+            sender_operations,
+            Source::Stream(Box::new(input)),
+            self.parse_options.clone(),
+        );
+        let processor = async {
+            if self.workers > 1 {
+                let mut processor =
+                    ShardedProcessor::build(receiver_operations, sender_results, self.workers)
+                        .with_decimals(decimals)
+                        .with_rounding(self.rounding)
+                        .with_policy(self.policy)
+                        .with_channel_capacity(self.channel_capacity);
+                processor.run().await?;
+                Ok::<_, anyhow::Error>(processor.summary().clone())
+            } else {
+                let mut processor = Processor::build(receiver_operations, sender_results)
+                    .with_decimals(decimals)
+                    .with_rounding(self.rounding)
+                    .with_policy(self.policy);
+                processor.run().await?;
+                Ok(processor.summary().clone())
+            }
+        };
+        let mut writer = Writer::build(receiver_results, self.format, output)
+            .with_mode(self.mode)
+            .with_delimiter(self.parse_options.delimiter)
+            .with_currency(self.with_currency)
+            .with_trim_zeros(self.trim_zeros);
+
+        let (reader_result, processor_result, writer_result) =
+            tokio::join!(reader.run(), processor, writer.run());
+        // same order as the binary: if the processor aborts, the reader fails as well
+        let mut summary = processor_result.context("Processor task failed")?;
+        summary.skipped = reader_result.context("Reader task failed")?;
+        writer_result.context("Writer task failed")?;
+        Ok(summary)
+    }
+}
+
+// Run the pipeline with the default options
+pub async fn run_pipeline<R, W>(input: R, output: W) -> Result<Summary>
+where
+    R: io::Read + Send + 'static,
+    W: io::Write,
+{
+    Pipeline::default().run(input, output).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::Rounding;
+    use crate::parser::ParseOptions;
+    use crate::pipeline::{run_pipeline, Pipeline};
+    use crate::processor::Policy;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,1,3,2.0
+withdrawal,1,4,1.5
+withdrawal,2,5,3.0
+deposit,2,x,1.0
+dispute,2,2,";
+
+    // The accounts are reported in no particular order
+    fn sorted_lines(output: &[u8]) -> Vec<String> {
+        let mut lines: Vec<String> = String::from_utf8(output.to_vec())
+            .expect("the output is not UTF-8")
+            .lines()
+            .map(String::from)
+            .collect();
+        lines[1..].sort();
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_run_pipeline() {
+        let mut output = vec![];
+        let summary = run_pipeline(INPUT.as_bytes(), &mut output)
+            .await
+            .expect("the pipeline failed");
+        assert_eq!(
+            sorted_lines(&output),
+            vec![
+                "client,available,held,total,locked",
+                "1,1.5000,0.0000,1.5000,false",
+                "2,0.0000,2.0000,2.0000,false",
+            ]
+        );
+        assert_eq!(summary.applied(), 5);
+        assert_eq!(summary.total_ignored(), 1);
+        assert_eq!(summary.skipped, 1);
+        assert!(!summary.is_clean());
+
+        // same reports with the sharded processor
+        let mut sharded = vec![];
+        Pipeline::default()
+            .with_workers(2)
+            .run(INPUT.as_bytes(), &mut sharded)
+            .await
+            .expect("the pipeline failed");
+        assert_eq!(sorted_lines(&sharded), sorted_lines(&output));
+    }
+
+    #[tokio::test]
+    async fn test_currencies() {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,1.0,
+deposit,1,2,2.0,EUR
+deposit,1,3,3.0,USD
+withdrawal,1,4,0.5,EUR";
+        let mut output = vec![];
+        Pipeline::default()
+            .with_currency(true)
+            .run(input.as_bytes(), &mut output)
+            .await
+            .expect("the pipeline failed");
+        assert_eq!(
+            sorted_lines(&output),
+            vec![
+                "client,currency,available,held,total,locked",
+                "1,,1.0000,0.0000,1.0000,false",
+                "1,EUR,1.5000,0.0000,1.5000,false",
+                "1,USD,3.0000,0.0000,3.0000,false",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_emit_empty_clients() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,7,2,5.0
+dispute,8,1,
+deposit,9,1,2.0";
+        let mut output = vec![];
+        run_pipeline(input.as_bytes(), &mut output)
+            .await
+            .expect("the pipeline failed");
+        assert_eq!(
+            sorted_lines(&output),
+            vec![
+                "client,available,held,total,locked",
+                "1,1.0000,0.0000,1.0000,false",
+            ]
+        );
+
+        for workers in [1, 3] {
+            let mut output = vec![];
+            Pipeline::default()
+                .with_policy(Policy {
+                    emit_empty_clients: true,
+                    ..Default::default()
+                })
+                .with_workers(workers)
+                .run(input.as_bytes(), &mut output)
+                .await
+                .expect("the pipeline failed");
+            assert_eq!(
+                sorted_lines(&output),
+                vec![
+                    "client,available,held,total,locked",
+                    "1,1.0000,0.0000,1.0000,false",
+                    "7,0.0000,0.0000,0.0000,false",
+                    "8,0.0000,0.0000,0.0000,false",
+                    // a tx reused by another client, rejected by the router with workers
+                    "9,0.0000,0.0000,0.0000,false",
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rounding() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.99999";
+        for workers in [1, 2] {
+            let mut output = vec![];
+            Pipeline::default()
+                .with_parse_options(ParseOptions {
+                    round_amounts: true,
+                    ..Default::default()
+                })
+                .with_rounding(Rounding::Truncate)
+                .with_workers(workers)
+                .run(input.as_bytes(), &mut output)
+                .await
+                .expect("the pipeline failed");
+            assert_eq!(
+                sorted_lines(&output),
+                vec![
+                    "client,available,held,total,locked",
+                    "1,1.9999,0.0000,1.9999,false",
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict() {
+        let pipeline = Pipeline::default()
+            .with_parse_options(ParseOptions {
+                strict: true,
+                ..Default::default()
+            })
+            .with_policy(Policy {
+                strict: true,
+                ..Default::default()
+            });
+        let mut output = vec![];
+        assert!(pipeline.run(INPUT.as_bytes(), &mut output).await.is_err());
+    }
+}
@@ -1,159 +1,658 @@
-use crate::account::Account;
-use crate::parser::{Operation, TypeOperation};
+use crate::account::{Account, AccountKey, FundsError, Rounding};
+use crate::channel::{Receiver, Sender};
+use crate::engine::Engine;
+use crate::parser::Operation;
+use crate::progress::Progress;
+use crate::rejects::Rejects;
+use crate::replay::Replay;
+use crate::self_check::{Reconciliation, SelfCheck};
+use crate::snapshot;
+use crate::store::AccountStore;
+use crate::summary::Summary;
 use crate::transaction::Transaction;
 use crate::{reader, writer};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::collections::{HashMap, HashSet};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-
-mod executors;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::watch;
 
 // Different type of status than an operation can result to
+#[derive(Debug)]
 pub enum OperationStatus {
     Successful(Transaction),
     UpdateTransaction(u32, Transaction),
+    // the lock of the account was cleared, no transaction is involved
+    Unlocked,
     AccountLocked,
     RepeatedTransaction,
+    // a deposit, withdrawal or transfer without amount
     EmptyAmount,
+    // a dispute, resolve or chargeback with an amount
+    UnexpectedAmount,
+    // a deposit, withdrawal or transfer of nothing
+    ZeroAmount,
     NonExistingTx,
+    NonExistingAccount,
     DisputeError,
-    Unknown, // gathers many type of statuses
+    // the funds would exceed the maximum value of the account
+    Overflow,
+    // a transfer without destination, to the same client or between different workers
+    InvalidTransfer,
+    // a withdrawal, transfer or block beyond the available funds (and the overdraft)
+    InsufficientFunds,
+    // an unlock without allow_unlock
+    UnlockNotAllowed,
+    // a withdrawal beyond what the client can still withdraw in this run
+    LimitExceeded,
+    // a dispute, resolve or chargeback (or a deposit which arrives disputed) while the
+    // transaction histories are not kept
+    NoHistory,
+    // a negative or not finite amount. The parser never lets them through, only the operations
+    // built by hand (e.g. with the engine as a library) can carry them
+    InvalidAmount,
+    // an internal state which should not happen (e.g. less held funds than a dispute holds),
+    // it is logged where it is returned
+    Unknown,
+}
+
+impl From<FundsError> for OperationStatus {
+    fn from(error: FundsError) -> Self {
+        match error {
+            FundsError::Overflow => Self::Overflow,
+            FundsError::InsufficientFunds => Self::InsufficientFunds,
+            FundsError::InvalidAmount => Self::InvalidAmount,
+        }
+    }
+}
+
+impl OperationStatus {
+    // Whether the operation was applied, any other status is why it was rejected
+    pub fn applied(&self) -> bool {
+        matches!(
+            self,
+            Self::Successful(_) | Self::UpdateTransaction(_, _) | Self::Unlocked
+        )
+    }
+
+    // Name of the status, used to report why an operation was ignored
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Successful(_) => "Successful",
+            Self::UpdateTransaction(_, _) => "UpdateTransaction",
+            Self::Unlocked => "Unlocked",
+            Self::AccountLocked => "AccountLocked",
+            Self::RepeatedTransaction => "RepeatedTransaction",
+            Self::EmptyAmount => "EmptyAmount",
+            Self::UnexpectedAmount => "UnexpectedAmount",
+            Self::ZeroAmount => "ZeroAmount",
+            Self::NonExistingTx => "NonExistingTx",
+            Self::NonExistingAccount => "NonExistingAccount",
+            Self::DisputeError => "DisputeError",
+            Self::Overflow => "Overflow",
+            Self::InvalidTransfer => "InvalidTransfer",
+            Self::InsufficientFunds => "InsufficientFunds",
+            Self::UnlockNotAllowed => "UnlockNotAllowed",
+            Self::LimitExceeded => "LimitExceeded",
+            Self::NoHistory => "NoHistory",
+            Self::InvalidAmount => "InvalidAmount",
+            Self::Unknown => "Unknown",
+        }
+    }
+}
+
+// Rules the operations are executed with
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Policy {
+    // disputes, resolves and chargebacks are still executed on a locked account,
+    // otherwise every operation on a locked account is rejected
+    pub allow_dispute_when_locked: bool,
+    // abort the whole run on the first rejected operation instead of ignoring it
+    pub strict: bool,
+    // how far below zero a withdrawal can leave the available funds, no overdraft by default
+    pub overdraft_limit: f64,
+    // report a zeroed account for the clients whose operations were all rejected, otherwise
+    // a client is only reported once one of its operations is applied
+    pub emit_empty_clients: bool,
+    // a resolve or a chargeback may carry an amount, up to what the disputed transaction still
+    // holds, and only settle that part of it
+    pub partial_disputes: bool,
+    // the unlock operations are applied, otherwise they are rejected. Meant for tests and
+    // admin inputs, a locked account is frozen for good in a regular run
+    pub allow_unlock: bool,
+    // how much each account can withdraw in the run, in total. No cap by default
+    pub max_withdrawal_per_client: Option<f64>,
+}
+
+// Configuration shared by the single, sharded and concurrent processors, so it is only wired
+// once whichever of them runs. See their with_* methods for each of the fields
+pub struct ProcessorOptions {
+    pub decimals: u32,
+    pub rounding: Rounding,
+    pub policy: Policy,
+    // the concurrent processor always keeps the histories, in full
+    pub history: bool,
+    pub history_retention: Option<usize>,
+    // the single processor has no channels of its own
+    pub channel_capacity: Option<usize>,
+    pub database: HashMap<AccountKey, Account>,
+    pub snapshot: Option<PathBuf>,
+    pub progress: Option<Arc<Progress>>,
+    pub rejects: Option<Rejects>,
+    pub replay: Option<Replay>,
+    pub shutdown: Option<watch::Receiver<bool>>,
 }
 
-pub struct Processor {
-    receiver: UnboundedReceiver<reader::Command>,
-    sender: UnboundedSender<writer::Command>,
-    // client_id: Account
-    // it represents a SQL database table, in a real scenario it would be a database access boxed trait
-    database: HashMap<u16, Account>,
-    // HashSet to keep track of the transaction history, this is not the ideal fix
-    // since it is taking x2 memory, but on the other hand the access time is O(1)
-    transactions: HashSet<u32>,
+// Generic over where the accounts are kept, a HashMap in memory by default
+pub struct Processor<S = HashMap<AccountKey, Account>> {
+    receiver: Receiver<reader::Command>,
+    sender: Sender<writer::Command>,
+    // where the operations are applied, the processor only feeds it from the channel
+    engine: Engine<S>,
+    // what happened with the operations
+    recorder: Recorder,
+    // file the database is saved to once all the operations are processed
+    snapshot: Option<PathBuf>,
+    // where the answers to the queries go, the results channel by default
+    query_sender: Option<Sender<writer::Command>>,
+    // once it turns true the processor stops and reports the balances computed so far
+    shutdown: Option<watch::Receiver<bool>>,
+    // ledger of the applied operations the accounts are reconciled with at the end
+    self_check: Option<SelfCheck>,
 }
 
 impl Processor {
-    pub fn build(
-        receiver: UnboundedReceiver<reader::Command>,
-        sender: UnboundedSender<writer::Command>,
+    pub fn build(receiver: Receiver<reader::Command>, sender: Sender<writer::Command>) -> Self {
+        Self::build_with_store(receiver, sender, HashMap::new())
+    }
+
+    pub fn with_options(self, options: ProcessorOptions) -> Self {
+        let mut processor = self
+            .with_decimals(options.decimals)
+            .with_rounding(options.rounding)
+            .with_policy(options.policy)
+            .with_history(options.history)
+            .with_database(options.database)
+            .with_snapshot(options.snapshot);
+        if let Some(history_retention) = options.history_retention {
+            processor = processor.with_history_retention(history_retention);
+        }
+        processor.recorder = Recorder::build(options.progress, options.rejects, options.replay);
+        if let Some(shutdown) = options.shutdown {
+            processor = processor.with_shutdown(shutdown);
+        }
+        processor
+    }
+}
+
+impl<S: AccountStore> Processor<S> {
+    // The accounts are kept in the given store, which may already have some
+    pub fn build_with_store(
+        receiver: Receiver<reader::Command>,
+        sender: Sender<writer::Command>,
+        store: S,
     ) -> Self {
         Self {
             receiver,
             sender,
-            database: Default::default(),
-            transactions: Default::default()
+            engine: Engine::build_with_store(store),
+            recorder: Default::default(),
+            snapshot: None,
+            query_sender: None,
+            shutdown: None,
+            self_check: None,
         }
     }
 
-    // Auxiliary function to process the corresponding Operation
-    fn process_data(&mut self, operation: Operation) {
-        let tx = operation.tx;
-        // If the transaction already exist, we exit. This should be done properly with error handling
-        if self.transactions.contains(&tx) && operation.type_operation == TypeOperation::deposit {
-            return;
+    pub fn with_decimals(mut self, decimals: u32) -> Self {
+        self.engine = self.engine.with_decimals(decimals);
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.engine = self.engine.with_rounding(rounding);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.engine = self.engine.with_policy(policy);
+        self
+    }
+
+    // See Engine::with_history_retention
+    pub fn with_history_retention(mut self, history_retention: usize) -> Self {
+        self.engine = self.engine.with_history_retention(history_retention);
+        self
+    }
+
+    // See Engine::with_history
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.engine = self.engine.with_history(history);
+        self
+    }
+
+    // Accounts to start from (e.g. loaded from a snapshot), see Engine::with_database
+    pub fn with_database(mut self, database: S) -> Self {
+        self.engine = self.engine.with_database(database);
+        if self.self_check.is_some() {
+            self = self.with_self_check();
         }
-        let client_id = operation.client;
-        let account = self
-            .database
-            .entry(client_id)
-            .or_insert_with(|| Account::build(client_id));
-        match Self::execute_operation(account, operation) {
-            OperationStatus::Successful(new_transaction) => {
-                account.transaction_history.insert(tx, new_transaction);
-                self.transactions.insert(tx);
-            }
-            OperationStatus::UpdateTransaction(tx, transaction) => {
-                *account
-                    .transaction_history
-                    .get_mut(&tx)
-                    .expect("unexpected error") = transaction;
-            }
-            // all the errors are ignored because of lack of time, they should be
-            // processed accordingly
-            _ => {}
+        self
+    }
+
+    // Keep a ledger of the applied operations apart from the accounts, see Processor::self_check
+    pub fn with_self_check(mut self) -> Self {
+        self.self_check = Some(SelfCheck::open(self.engine.accounts()));
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    // Answer the queries through another channel (e.g. straight to the writer when the results
+    // are merged before being reported)
+    pub fn with_query_sender(mut self, query_sender: Sender<writer::Command>) -> Self {
+        self.query_sender = Some(query_sender);
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.recorder.progress = Some(progress);
+        self
+    }
+
+    pub fn with_rejects(mut self, rejects: Rejects) -> Self {
+        self.recorder.rejects = Some(rejects);
+        self
+    }
+
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.recorder.replay = Some(replay);
+        self
+    }
+
+    // Records what happened with the operations somewhere else, e.g. for a sharded worker
+    pub(crate) fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Accounts processed so far
+    pub fn store(&self) -> &S {
+        self.engine.accounts()
+    }
+
+    // Counters of the operations processed so far
+    pub fn summary(&self) -> &Summary {
+        &self.recorder.summary
+    }
+
+    // The accounts reconciled with the ledger of the operations applied so far, None without
+    // with_self_check
+    pub fn self_check(&self) -> Option<Reconciliation> {
+        self.self_check
+            .as_ref()
+            .map(|self_check| self_check.check(self.engine.accounts()))
+    }
+
+    // Auxiliary function to process the corresponding Operation, the status tells whether it
+    // was applied or why it was rejected (it is already recorded either way)
+    // It only fails if the policy makes the whole run abort
+    fn process_data(&mut self, operation: Operation) -> Result<OperationStatus> {
+        let status = self.engine.apply(operation.clone());
+        if !status.applied() {
+            self.recorder
+                .reject(&operation, &status, self.engine.policy())?;
+            return Ok(status);
+        }
+        if let Some(self_check) = &mut self.self_check {
+            self_check.record(self.engine.accounts(), &operation, &status);
+        }
+        self.recorder.record_applied(&operation)?;
+        Ok(status)
+    }
+
+    // Send the current accounts of the client (one per currency), nothing is sent if it does
+    // not exist yet. Every account is looked at, the queries are not expected to be frequent
+    async fn answer_query(&self, client_id: u16) -> Result<()> {
+        let sender = self.query_sender.as_ref().unwrap_or(&self.sender);
+        let accounts: Vec<Account> = self
+            .engine
+            .accounts()
+            .iter()
+            .filter(|account| account.client_id() == client_id)
+            .cloned()
+            .collect();
+        if accounts.is_empty() {
+            tracing::debug!(client = client_id, "query of a non existing account");
+        }
+        for account in accounts {
+            sender.send(writer::Command::Answer(account)).await?;
         }
+        Ok(())
     }
 
     pub async fn run(&mut self) -> Result<()> {
         // it reads all the messages received from the queue
-        while let Some(operation) = self.receiver.recv().await {
+        loop {
+            // the shutdown goes first, otherwise a long queue would be processed before it
+            let command = tokio::select! {
+                biased;
+                _ = shutdown_requested(&mut self.shutdown) => None,
+                command = self.receiver.recv() => match command {
+                    Some(command) => Some(command),
+                    None => return Ok(()),
+                },
+            };
             // If the received command contains an operation
-            match operation {
+            match command {
+                // the queued operations are left behind, only what was applied is reported
+                None => return self.close().await,
                 // If the received command closes the connection: report the data to print it out
                 // This is not done ideally, it was simplified for the sake of the exercise
                 // The best solution would be to have a command per client to see their balance
                 // and an administrator program would request the account balance for each existing account,
                 // or an administrator would have access to the database where is everything
-                reader::Command::CloseConnection => {
-                    for account in self.database.values() {
-                        self.sender.send(writer::Command::Data(account.clone()))?;
+                Some(reader::Command::CloseConnection) => {
+                    // with several senders, operations can still be queued behind the close,
+                    // they are applied before reporting so none of them is lost
+                    while let Some(command) = self.receiver.try_recv() {
+                        match command {
+                            reader::Command::Data(operation) => {
+                                self.process_data(operation)?;
+                            }
+                            reader::Command::Query(client_id) => {
+                                self.answer_query(client_id).await?
+                            }
+                            reader::Command::CloseConnection => {}
+                        }
                     }
-                    self.sender.send(writer::Command::CloseConnection)?;
-                    break;
+                    return self.close().await;
                 }
-                reader::Command::Data(operation) => {
-                    // it is only possible to create an account with a deposit
-                    if !self.database.contains_key(&operation.client)
-                        && operation.type_operation != TypeOperation::deposit
-                    {
-                        continue;
-                    }
-                    self.process_data(operation);
+                Some(reader::Command::Data(operation)) => {
+                    self.process_data(operation)?;
+                }
+                Some(reader::Command::Query(client_id)) => {
+                    self.answer_query(client_id).await?;
                 }
             }
         }
+    }
+
+    // Report every account and close the results channel
+    async fn close(&mut self) -> Result<()> {
+        for key in self.recorder.rejected_accounts.drain() {
+            self.engine.open_account(&key);
+        }
+        self.recorder.flush()?;
+        // saved before the reports, so the snapshot is there once the output is written
+        if let Some(path) = &self.snapshot {
+            snapshot::save(path, self.engine.accounts().iter())?;
+        }
+        report(&self.sender, self.engine.accounts().iter().cloned()).await
+    }
+}
+
+// Where the outcome of the operations is recorded: they are counted in the summary, logged and
+// written to the replay or the rejects file if any. The sharded and concurrent processors have
+// one per task (see Recorder::fork), their summaries are merged at the end
+#[derive(Default)]
+pub(crate) struct Recorder {
+    pub(crate) summary: Summary,
+    // accounts of the rejected operations, created empty at the end with emit_empty_clients
+    pub(crate) rejected_accounts: HashSet<AccountKey>,
+    pub(crate) progress: Option<Arc<Progress>>,
+    // where the rejected operations are written to, besides being logged
+    pub(crate) rejects: Option<Rejects>,
+    // where the applied operations are written to, to reproduce the run
+    pub(crate) replay: Option<Replay>,
+}
+
+impl Recorder {
+    pub(crate) fn build(
+        progress: Option<Arc<Progress>>,
+        rejects: Option<Rejects>,
+        replay: Option<Replay>,
+    ) -> Self {
+        Self {
+            progress,
+            rejects,
+            replay,
+            ..Default::default()
+        }
+    }
+
+    // Same progress and files, nothing recorded yet
+    pub(crate) fn fork(&self) -> Self {
+        Self::build(
+            self.progress.clone(),
+            self.rejects.clone(),
+            self.replay.clone(),
+        )
+    }
+
+    pub(crate) fn record_applied(&mut self, operation: &Operation) -> Result<()> {
+        tracing::debug!(
+            client = operation.client,
+            tx = operation.tx,
+            operation = ?operation.type_operation,
+            "operation applied"
+        );
+        self.summary.record_applied(operation.type_operation);
+        if let Some(progress) = &self.progress {
+            progress.record_applied();
+        }
+        match &self.replay {
+            Some(replay) => replay.record(operation),
+            None => Ok(()),
+        }
+    }
+
+    // In strict mode a rejected operation aborts the whole run, otherwise it is only recorded
+    pub(crate) fn reject(
+        &mut self,
+        operation: &Operation,
+        status: &OperationStatus,
+        policy: &Policy,
+    ) -> Result<()> {
+        if policy.strict {
+            return Err(anyhow!(
+                "Rejected operation {:?} ({})",
+                operation,
+                status.reason()
+            ));
+        }
+        self.record_rejection(operation, status, policy)
+    }
+
+    // Single place where a rejected operation is recorded: it is counted in the summary,
+    // logged and written to the rejects file if any. The errors do not stop the processing,
+    // they are only reported
+    fn record_rejection(
+        &mut self,
+        operation: &Operation,
+        status: &OperationStatus,
+        policy: &Policy,
+    ) -> Result<()> {
+        // activity after an account was frozen and malformed rows are reported on their own
+        let message = match status {
+            OperationStatus::AccountLocked => "operation on locked account",
+            OperationStatus::EmptyAmount => "operation without amount",
+            OperationStatus::UnexpectedAmount => "dispute operation with an amount",
+            OperationStatus::InsufficientFunds => "operation without enough funds",
+            _ => "operation ignored",
+        };
+        tracing::warn!(
+            client = operation.client,
+            tx = operation.tx,
+            operation = ?operation.type_operation,
+            reason = ?status,
+            "{}",
+            message
+        );
+        self.summary.record_ignored(status);
+        if policy.emit_empty_clients {
+            self.rejected_accounts.insert(AccountKey::build(
+                operation.client,
+                operation.currency.clone(),
+            ));
+        }
+        match &self.rejects {
+            Some(rejects) => rejects.record(operation, status),
+            None => Ok(()),
+        }
+    }
+
+    // Once all the operations are processed
+    pub(crate) fn flush(&self) -> Result<()> {
+        if let Some(rejects) = &self.rejects {
+            rejects.flush()?;
+        }
+        if let Some(replay) = &self.replay {
+            replay.flush()?;
+        }
         Ok(())
     }
 }
 
+// Send the accounts to the writer and close the results channel. All the work is done by then,
+// so if the writer is already gone (e.g. it failed) the reports are dropped with a warning
+// instead of failing the run
+pub(crate) async fn report(
+    sender: &Sender<writer::Command>,
+    accounts: impl IntoIterator<Item = Account>,
+) -> Result<()> {
+    let commands = accounts
+        .into_iter()
+        .map(writer::Command::Data)
+        .chain(std::iter::once(writer::Command::CloseConnection));
+    for command in commands {
+        if let Err(error) = sender.send(command).await {
+            if !sender.is_closed() {
+                return Err(error);
+            }
+            tracing::warn!("the results channel is closed, the accounts are not reported");
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Wait until the shutdown is requested, forever if there is no shutdown or nobody can request it
+pub(crate) async fn shutdown_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        if shutdown.wait_for(|requested| *requested).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
 #[cfg(test)]
 mod test {
-    use crate::account::Account;
-    use crate::parser::{Operation, TypeOperation};
-    use crate::processor::Processor;
-    use crate::transaction::{Transaction, TransactionType};
+    use crate::account::{Account, AccountKey};
+    use crate::channel;
+    use crate::engine::Engine;
+    use crate::parser::{parse_rows, Operation, TypeOperation};
+    use crate::processor::{OperationStatus, Policy, Processor, Recorder};
+    use crate::snapshot;
+    use crate::transaction::{DisputeState, Transaction, TransactionType};
     use crate::{reader, writer};
-    use std::collections::HashMap;
-    use tokio::sync::mpsc;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::watch;
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::Registry;
 
-    async fn test_all(list_operations: Vec<Operation>, expected_results: HashMap<u16, Account>) {
-        let (sender_operations, receiver_operations) = mpsc::unbounded_channel::<reader::Command>();
-        let (sender_results, mut receiver_results) = mpsc::unbounded_channel::<writer::Command>();
+    // Run the processor over the operations and collect the reported accounts
+    async fn run_processor(
+        list_operations: Vec<Operation>,
+        capacity: Option<usize>,
+    ) -> Vec<Account> {
+        let (sender_operations, receiver_operations) =
+            channel::channel::<reader::Command>(capacity);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(capacity);
         let mut processor = Processor::build(receiver_operations, sender_results);
 
         let start_receiver = tokio::spawn(async move {
             for operation in list_operations {
                 assert!(sender_operations
                     .send(reader::Command::Data(operation))
+                    .await
                     .is_ok());
             }
             assert!(sender_operations
                 .send(reader::Command::CloseConnection)
+                .await
                 .is_ok());
         });
 
-        assert!(processor.run().await.is_ok());
+        // the processor runs on its own task, otherwise a bounded results channel would never be drained
+        let start_processor = tokio::spawn(async move { processor.run().await });
 
+        let mut results = vec![];
         while let Some(data) = receiver_results.recv().await {
             match data {
-                writer::Command::Data(data) => {
-                    assert!(check_data(data, &expected_results));
-                }
+                writer::Command::Data(data) => results.push(data),
                 _ => break,
             }
         }
 
+        assert!(start_processor.await.expect("processor panicked").is_ok());
         assert!(start_receiver.await.is_ok());
+        results
     }
 
-    fn check_data(input: Account, expected_resuts: &HashMap<u16, Account>) -> bool {
-        match expected_resuts.get(&input.client_id) {
-            Some(result_account) => {
-                if *result_account == input {
-                    true
-                } else {
-                    false
+    async fn test_all(list_operations: Vec<Operation>, expected_results: HashMap<u16, Account>) {
+        for data in run_processor(list_operations, None).await {
+            assert_funds_conserved(&data);
+            assert!(check_data(data, &expected_results));
+        }
+    }
+
+    // held + available must always match what the transaction history says the client owns:
+    // deposits minus withdrawals (charged back deposits are gone), of which the disputed ones are held
+    fn assert_funds_conserved(account: &Account) {
+        let units = |amount: f64| (amount * 10_000.0).round() as i128;
+        let mut total = 0;
+        let mut held = 0;
+        for transaction in account.transaction_history.values() {
+            match transaction.type_transaction {
+                TransactionType::Deposit
+                    if transaction.dispute_state == DisputeState::ChargedBack => {}
+                TransactionType::Deposit | TransactionType::TransferIn => {
+                    total += units(transaction.amount)
                 }
+                TransactionType::Withdrawal | TransactionType::TransferOut => {
+                    total -= units(transaction.amount)
+                }
+            }
+            if transaction.disputed() {
+                held += units(transaction.amount);
             }
+        }
+        assert_eq!(account.held_funds as i128, held);
+        assert_eq!(
+            account.held_funds as i128 + account.available_funds as i128,
+            total
+        );
+    }
+
+    fn check_data(input: Account, expected_resuts: &HashMap<u16, Account>) -> bool {
+        match expected_resuts.get(&input.client_id) {
+            Some(result_account) => *result_account == input,
             None => false,
         }
     }
@@ -170,68 +669,1610 @@ mod test {
         test_all(list_operations, expected_result).await;
     }
 
-    fn prepare_simple_test() -> (Vec<Operation>, HashMap<u16, Account>) {
-        let list_operations = vec![
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 1,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 3,
-                tx: 0,
-                amount: Some(2.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 2,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::withdrawal,
-                client: 1,
-                tx: 202,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 3,
-                tx: 1,
-                amount: Some(1.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 3,
-                tx: 1,
-                amount: Some(1.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 5,
-                tx: 4,
-                amount: Some(5.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::withdrawal,
-                client: 10,
-                tx: 15,
-                amount: Some(5.000100),
+    // Processor to feed directly through process_data, without channels
+    fn build_processor() -> Processor {
+        let (_, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, _) = channel::channel::<writer::Command>(None);
+        Processor::build(receiver_operations, sender_results)
+    }
+
+    // Run operations which all belong to the same client and return its final account
+    async fn run_single_client(list_operations: Vec<Operation>) -> Account {
+        let mut results = run_processor(list_operations, None).await;
+        assert_eq!(results.len(), 1);
+        assert_funds_conserved(&results[0]);
+        results.remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_dispute_twice() {
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+        ])
+        .await;
+        // the funds are held only once
+        assert_eq!(account.available_funds(), "0.0000");
+        assert_eq!(account.held_funds(), "10.0000");
+        assert!(account.transaction_history[&1].disputed());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_spent_deposit() {
+        let spent = vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(100.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(60.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+        ];
+        // the whole deposit is held, the client owes what was withdrawn
+        let account = run_single_client(spent.clone()).await;
+        assert_eq!(account.available_funds(), "-60.0000");
+        assert_eq!(account.held_funds(), "100.0000");
+        assert_eq!(account.total(), "40.0000");
+
+        let mut resolved = spent.clone();
+        resolved.push(Operation::new(TypeOperation::resolve, 1, 1, None));
+        let account = run_single_client(resolved).await;
+        assert_eq!(account.available_funds(), "40.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+
+        let mut charged_back = spent;
+        charged_back.push(Operation::new(TypeOperation::chargeback, 1, 1, None));
+        let account = run_single_client(charged_back).await;
+        assert_eq!(account.available_funds(), "-60.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert_eq!(account.total(), "-60.0000");
+        assert!(account.locked());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_resolve_dispute() {
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "0.0000");
+        assert_eq!(account.held_funds(), "10.0000");
+        assert!(account.transaction_history[&1].disputed());
+
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "10.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert!(!account.transaction_history[&1].disputed());
+        // the history tells it went through a dispute
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::Resolved
+        );
+    }
+
+    #[test]
+    fn test_dispute_states() {
+        let mut processor = build_processor().with_policy(Policy {
+            allow_dispute_when_locked: true,
+            ..Default::default()
+        });
+        let state = |processor: &Processor| {
+            let transaction = &processor.store()[&AccountKey::from(1)].transaction_history[&1];
+            // the type never changes, only the state does
+            assert_eq!(transaction.type_transaction, TransactionType::Deposit);
+            transaction.dispute_state
+        };
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)))
+            .is_ok());
+        assert_eq!(state(&processor), DisputeState::None);
+        for (type_operation, expected) in [
+            (TypeOperation::dispute, DisputeState::Disputed),
+            (TypeOperation::resolve, DisputeState::Resolved),
+            // a resolved transaction cannot be resolved again, but it can be disputed again
+            (TypeOperation::resolve, DisputeState::Resolved),
+            (TypeOperation::dispute, DisputeState::Disputed),
+            (TypeOperation::chargeback, DisputeState::ChargedBack),
+            // final
+            (TypeOperation::dispute, DisputeState::ChargedBack),
+            (TypeOperation::resolve, DisputeState::ChargedBack),
+        ] {
+            assert!(processor
+                .process_data(Operation::new(type_operation, 1, 1, None))
+                .is_ok());
+            assert_eq!(state(&processor), expected);
+        }
+        assert_eq!(processor.summary().ignored["DisputeError"], 3);
+
+        // withdrawals cannot be disputed
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(1.0)),
+            Operation::new(TypeOperation::dispute, 1, 2, None),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].transaction_history[&2].dispute_state,
+            DisputeState::None
+        );
+        assert_eq!(processor.summary().ignored["DisputeError"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_without_dispute() {
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 2, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "10.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert!(!account.transaction_history[&1].disputed());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_twice() {
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "10.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_without_dispute() {
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "10.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert!(!account.locked());
+
+        // a resolved dispute cannot be charged back either
+        let account = run_single_client(vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+        ])
+        .await;
+        assert_eq!(account.available_funds(), "10.0000");
+        assert!(!account.locked());
+    }
+
+    #[tokio::test]
+    async fn test_cross_client_tx_collision() {
+        let mut results = run_processor(
+            vec![
+                Operation::new(TypeOperation::deposit, 1, 5, Some(10.0)),
+                Operation::new(TypeOperation::deposit, 2, 5, Some(20.0)),
+                Operation::new(TypeOperation::deposit, 2, 6, Some(1.0)),
+                Operation::new(TypeOperation::withdrawal, 2, 5, Some(1.0)),
+            ],
+            None,
+        )
+        .await;
+        results.sort_by_key(|account| account.client_id);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].available_funds(), "10.0000");
+        assert_eq!(results[1].available_funds(), "1.0000");
+        assert!(!results[1].transaction_history.contains_key(&5));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_other_client_tx() {
+        let mut results = run_processor(
+            vec![
+                Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+                Operation::new(TypeOperation::deposit, 2, 2, Some(5.0)),
+                Operation::new(TypeOperation::dispute, 2, 1, None),
+                Operation::new(TypeOperation::chargeback, 2, 1, None),
+            ],
+            None,
+        )
+        .await;
+        results.sort_by_key(|account| account.client_id);
+        for account in &results {
+            assert_eq!(account.held_funds(), "0.0000");
+            assert!(!account.locked());
+        }
+        assert_eq!(results[0].available_funds(), "10.0000");
+        assert_eq!(results[1].available_funds(), "5.0000");
+    }
+
+    #[test]
+    fn test_foreign_transaction() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(1.0)),
+            Operation::new(TypeOperation::deposit, 2, 2, Some(5.0)),
+            // client 2's deposit referenced by client 1
+            Operation::new(TypeOperation::dispute, 1, 2, None),
+            Operation::new(TypeOperation::resolve, 1, 2, None),
+            Operation::new(TypeOperation::chargeback, 1, 2, None),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["NonExistingTx"], 3);
+        let owner = &processor.store()[&AccountKey::from(2)];
+        assert_eq!(owner.available_funds(), "5.0000");
+        assert_eq!(owner.held_funds(), "0.0000");
+        assert_eq!(
+            owner.transaction_history[&2].dispute_state,
+            DisputeState::None
+        );
+        assert!(!owner.locked());
+
+        // even if the transaction ended up in the history of another account
+        let mut account = processor.store()[&AccountKey::from(1)].clone();
+        account
+            .transaction_history
+            .insert(2, owner.transaction_history[&2].clone());
+        for type_operation in [TypeOperation::dispute, TypeOperation::chargeback] {
+            assert!(matches!(
+                Engine::execute_operation(
+                    &mut account,
+                    Operation::new(type_operation, 1, 2, None),
+                    &Policy::default()
+                ),
+                OperationStatus::NonExistingTx
+            ));
+        }
+        assert_eq!(account.held_funds(), "0.0000");
+    }
+
+    // Layer which keeps every event as its level and its formatted fields
+    #[derive(Clone, Default)]
+    struct CaptureLayer(Arc<Mutex<Vec<(Level, String)>>>);
+
+    struct FieldsVisitor(String);
+
+    impl Visit for FieldsVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut visitor = FieldsVisitor(String::new());
+            event.record(&mut visitor);
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), visitor.0));
+        }
+    }
+
+    #[test]
+    fn test_process_data_status() {
+        let mut processor = build_processor();
+        let mut process = |operation| {
+            processor
+                .process_data(operation)
+                .expect("only a strict run fails")
+        };
+        assert!(matches!(
+            process(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0))),
+            OperationStatus::Successful(_)
+        ));
+        assert!(matches!(
+            process(Operation::new(TypeOperation::deposit, 1, 1, Some(5.0))),
+            OperationStatus::RepeatedTransaction
+        ));
+        assert!(matches!(
+            process(Operation::new(TypeOperation::dispute, 1, 7, None)),
+            OperationStatus::NonExistingTx
+        ));
+        assert!(matches!(
+            process(Operation::new(TypeOperation::withdrawal, 1, 2, Some(2.5))),
+            OperationStatus::Successful(Transaction {
+                type_transaction: TransactionType::Withdrawal,
+                amount,
+                ..
+            }) if amount == 2.5
+        ));
+        assert!(matches!(
+            process(Operation::new(TypeOperation::dispute, 1, 1, None)),
+            OperationStatus::UpdateTransaction(
+                1,
+                Transaction {
+                    dispute_state: DisputeState::Disputed,
+                    ..
+                }
+            )
+        ));
+        // the rejections are still recorded
+        assert_eq!(processor.summary().total_ignored(), 2);
+        assert_eq!(processor.summary().applied(), 3);
+    }
+
+    #[test]
+    fn test_ignored_operation_logged() {
+        let layer = CaptureLayer::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(layer.clone()));
+
+        let mut processor = build_processor();
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 2, None))
+            .is_ok());
+
+        let events = layer.0.lock().unwrap();
+        let warnings: Vec<&String> = events
+            .iter()
+            .filter(|(level, _)| *level == Level::WARN)
+            .map(|(_, fields)| fields)
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("client=1"));
+        assert!(warnings[0].contains("tx=2"));
+        assert!(warnings[0].contains("reason=NonExistingTx"));
+    }
+
+    #[test]
+    fn test_malformed_amounts() {
+        let layer = CaptureLayer::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(layer.clone()));
+
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, None),
+            Operation::new(TypeOperation::dispute, 1, 1, Some(10.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["EmptyAmount"], 1);
+        assert_eq!(processor.summary().ignored["UnexpectedAmount"], 1);
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "10.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].held_funds(),
+            "0.0000"
+        );
+
+        let events = layer.0.lock().unwrap();
+        let warnings: Vec<&String> = events
+            .iter()
+            .filter(|(level, _)| *level == Level::WARN)
+            .map(|(_, fields)| fields)
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("operation without amount"));
+        assert!(warnings[0].contains("tx=2"));
+        assert!(warnings[1].contains("dispute operation with an amount"));
+        assert!(warnings[1].contains("tx=1"));
+    }
+
+    fn in_currency(operation: Operation, currency: &str) -> Operation {
+        Operation {
+            currency: Some(currency.to_string()),
+            ..operation
+        }
+    }
+
+    #[test]
+    fn test_currencies() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            in_currency(
+                Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+                "EUR",
+            ),
+            in_currency(
+                Operation::new(TypeOperation::deposit, 1, 3, Some(7.0)),
+                "USD",
+            ),
+            in_currency(
+                Operation::new(TypeOperation::withdrawal, 1, 4, Some(2.0)),
+                "EUR",
+            ),
+            // the funds of another currency cannot be used
+            in_currency(
+                Operation::new(TypeOperation::withdrawal, 1, 5, Some(4.0)),
+                "EUR",
+            ),
+            in_currency(
+                Operation::new(TypeOperation::withdrawal, 1, 6, Some(1.0)),
+                "GBP",
+            ),
+            // the dispute is looked up in the account of its currency
+            Operation::new(TypeOperation::dispute, 1, 3, None),
+            in_currency(Operation::new(TypeOperation::dispute, 1, 3, None), "USD"),
+            in_currency(Operation::new(TypeOperation::chargeback, 1, 3, None), "USD"),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.store().len(), 3);
+        let default = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(default.available_funds(), "10.0000");
+        assert!(!default.locked());
+        let eur = &processor.store()[&AccountKey::build(1, Some("EUR".to_string()))];
+        assert_eq!(eur.available_funds(), "3.0000");
+        assert_eq!(eur.currency(), Some("EUR"));
+        // only the account of the charged back currency is locked
+        let usd = &processor.store()[&AccountKey::build(1, Some("USD".to_string()))];
+        assert_eq!(usd.total(), "0.0000");
+        assert!(usd.locked());
+        assert_eq!(processor.summary().ignored["InsufficientFunds"], 1);
+        assert_eq!(processor.summary().ignored["NonExistingAccount"], 1);
+        assert_eq!(processor.summary().ignored["NonExistingTx"], 1);
+    }
+
+    #[test]
+    fn test_self_check() {
+        let (list_operations, _) = prepare_complex_test();
+        let mut processor = build_processor().with_self_check();
+        for operation in list_operations {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        let reconciliation = processor.self_check().expect("the self-check is enabled");
+        assert!(reconciliation.is_consistent(), "{}", reconciliation);
+        assert_eq!(reconciliation.checked, processor.store().len() as u64);
+        // the resolve of client 5 would overflow, so it is rejected as a whole: if it released
+        // the held funds before failing, they would not match the ledger anymore
+        let account = &processor.store()[&AccountKey::from(5)];
+        assert_eq!(account.held_funds(), "500000000000000.0000");
+        assert_eq!(account.total(), "1000000000000000.0000");
+
+        assert!(build_processor().self_check().is_none());
+    }
+
+    #[test]
+    fn test_dispute_by_reference() {
+        let data = "type,client,tx,amount,ref
+deposit,1,1,10.0,abc
+deposit,1,2,5.0,def
+deposit,2,3,1.0,xyz
+deposit,1,4,1.0,abc
+dispute,1,0,,xyz
+dispute,1,0,,
+dispute,1,0,,abc
+resolve,1,2,,abc
+chargeback,1,0,,abc";
+        let (operations, skipped) =
+            parse_rows(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(skipped, 0);
+        assert_eq!(operations[0].reference.as_deref(), Some("abc"));
+        assert_eq!(operations[5].reference, None);
+        let mut processor = build_processor();
+        for operation in operations {
+            assert!(processor.process_data(operation).is_ok());
+        }
+
+        // the reference targets the first deposit created with it
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::ChargedBack
+        );
+        assert_eq!(account.available_funds(), "6.0000");
+        assert!(account.locked());
+        // the tx goes first: the deposit 2 was not disputed
+        assert_eq!(processor.summary().ignored["DisputeError"], 1);
+        // the reference of another client is not looked up, nor a missing one
+        assert_eq!(processor.summary().ignored["NonExistingTx"], 2);
+        assert_eq!(processor.summary().disputes, 1);
+        assert_eq!(processor.summary().chargebacks, 1);
+    }
+
+    #[test]
+    fn test_pre_disputed_deposit() {
+        let data = "type,client,tx,amount,disputed
+deposit,1,1,10.0,true
+deposit,1,2,5.0,
+deposit,2,3,1.0,disputed
+resolve,1,1,,
+chargeback,2,3,,";
+        let (operations, skipped) =
+            parse_rows(data.as_bytes(), &Default::default()).expect("cannot parse");
+        assert_eq!(skipped, 0);
+        let mut processor = build_processor().with_self_check();
+        for operation in operations {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        // resolved directly, without a dispute in the file
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::Resolved
+        );
+        assert_eq!(account.available_funds(), "15.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        let account = &processor.store()[&AccountKey::from(2)];
+        assert_eq!(account.available_funds(), "0.0000");
+        assert!(account.locked());
+        assert!(processor.summary().is_clean());
+        assert!(processor.self_check().expect("enabled").is_consistent());
+
+        // without the column the resolve has no dispute to settle
+        let data = "type,client,tx,amount\ndeposit,1,1,10.0\nresolve,1,1,";
+        let (operations, _) =
+            parse_rows(data.as_bytes(), &Default::default()).expect("cannot parse");
+        let mut processor = build_processor();
+        for operation in operations {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["DisputeError"], 1);
+    }
+
+    #[test]
+    fn test_withdrawal_cap() {
+        let mut processor = build_processor().with_policy(Policy {
+            max_withdrawal_per_client: Some(10.0),
+            ..Default::default()
+        });
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(100.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(6.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(4.0)),
+            // over the cap, even though the funds are there
+            Operation::new(TypeOperation::withdrawal, 1, 4, Some(0.0001)),
+            // the rejected ones do not count
+            Operation::new(TypeOperation::deposit, 2, 5, Some(100.0)),
+            Operation::new(TypeOperation::withdrawal, 2, 6, Some(10.5)),
+            Operation::new(TypeOperation::withdrawal, 2, 7, Some(10.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["LimitExceeded"], 2);
+        assert_eq!(processor.summary().withdrawals, 3);
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "90.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(2)].available_funds(),
+            "90.0000"
+        );
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(10.5)),
+            Operation::new(TypeOperation::deposit, 2, 3, Some(1.0)),
+            transfer(1, 2, 4, 11.0),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        // counted on their own, not as unknown errors
+        assert_eq!(processor.summary().ignored["InsufficientFunds"], 2);
+        assert!(!processor.summary().ignored.contains_key("Unknown"));
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "10.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(2)].available_funds(),
+            "1.0000"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(3.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 4, Some(100.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 2, None),
+            Operation::new(TypeOperation::chargeback, 1, 2, None),
+            Operation::new(TypeOperation::deposit, 1, 5, Some(1.0)),
+            Operation::new(TypeOperation::dispute, 2, 9, None),
+            Operation::new(TypeOperation::deposit, 3, 1, Some(1.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+
+        let summary = processor.summary();
+        assert_eq!(summary.deposits, 2);
+        assert_eq!(summary.withdrawals, 1);
+        assert_eq!(summary.disputes, 2);
+        assert_eq!(summary.resolves, 1);
+        assert_eq!(summary.chargebacks, 1);
+        assert_eq!(summary.applied(), 7);
+        assert_eq!(
+            summary.ignored,
+            BTreeMap::from([
+                ("AccountLocked", 1),
+                ("NonExistingAccount", 1),
+                ("InsufficientFunds", 1),
+                ("RepeatedTransaction", 1),
+            ])
+        );
+        assert_eq!(summary.total_ignored(), 4);
+    }
+
+    fn lock_account(processor: &mut Processor) {
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert!(processor.store()[&AccountKey::from(1)].locked());
+    }
+
+    #[test]
+    fn test_locked_account() {
+        let mut processor = build_processor();
+        lock_account(&mut processor);
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 3, Some(1.0)))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 2, None))
+            .is_ok());
+
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "5.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert_eq!(processor.summary().ignored["AccountLocked"], 2);
+    }
+
+    #[test]
+    fn test_chargeback_twice() {
+        // even if the disputes go on in locked accounts, a transaction is only charged back once
+        let mut processor = build_processor().with_policy(Policy {
+            allow_dispute_when_locked: true,
+            ..Default::default()
+        });
+        lock_account(&mut processor);
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::chargeback, 1, 1, None))
+            .is_ok());
+        // and a locked account can be charged back again, the lock stays as it is
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 2, None))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::chargeback, 1, 2, None))
+            .is_ok());
+
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "0.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert!(account.locked());
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::ChargedBack
+        );
+        assert_eq!(processor.summary().ignored["DisputeError"], 1);
+        assert_eq!(processor.summary().chargebacks, 2);
+    }
+
+    #[test]
+    fn test_chargeback_without_dispute_status() {
+        let mut account = Account::build(1);
+        assert!(account.add_funds(10.0).is_ok());
+        account.transaction_history.insert(
+            1,
+            Transaction {
+                client_id: 1,
+                amount: 10.0,
+                type_transaction: TransactionType::Deposit,
+                dispute_state: DisputeState::None,
+                settled: Default::default(),
             },
-            Operation {
-                type_operation: TypeOperation::withdrawal,
-                client: 5,
-                tx: 105,
-                amount: Some(5.000200),
+        );
+        assert!(matches!(
+            Engine::execute_operation(
+                &mut account,
+                Operation::new(TypeOperation::chargeback, 1, 1, None),
+                &Policy::default()
+            ),
+            OperationStatus::DisputeError
+        ));
+        assert_eq!(account.available_funds(), "10.0000");
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn test_invalid_amounts() {
+        let policy = Policy::default();
+        let mut account = Account::build(1);
+        assert!(account.add_funds(10.0).is_ok());
+        // the parser rejects them, but not the operations built by hand
+        for (type_operation, amount) in [
+            (TypeOperation::deposit, -1.0),
+            (TypeOperation::deposit, f64::INFINITY),
+            (TypeOperation::withdrawal, f64::NAN),
+        ] {
+            assert!(matches!(
+                Engine::execute_operation(
+                    &mut account,
+                    Operation::new(type_operation, 1, 2, Some(amount)),
+                    &policy
+                ),
+                OperationStatus::InvalidAmount
+            ));
+        }
+        account.transaction_history.insert(
+            1,
+            Transaction {
+                client_id: 1,
+                amount: -5.0,
+                type_transaction: TransactionType::Deposit,
+                dispute_state: DisputeState::None,
+                settled: Default::default(),
             },
-            Operation {
-                type_operation: TypeOperation::withdrawal,
-                client: 5,
-                tx: 105,
-                amount: Some(5.00000),
+        );
+        assert!(matches!(
+            Engine::execute_operation(
+                &mut account,
+                Operation::new(TypeOperation::dispute, 1, 1, None),
+                &policy
+            ),
+            OperationStatus::InvalidAmount
+        ));
+        assert_eq!(account.available_funds(), "10.0000");
+
+        // a disputed transaction whose funds are not held, e.g. from an edited snapshot
+        account.transaction_history.insert(
+            1,
+            Transaction {
+                client_id: 1,
+                amount: 5.0,
+                type_transaction: TransactionType::Deposit,
+                dispute_state: DisputeState::Disputed,
+                settled: Default::default(),
             },
+        );
+        for type_operation in [TypeOperation::resolve, TypeOperation::chargeback] {
+            assert!(matches!(
+                Engine::execute_operation(
+                    &mut account,
+                    Operation::new(type_operation, 1, 1, None),
+                    &policy
+                ),
+                OperationStatus::Unknown
+            ));
+        }
+        assert_eq!(account.available_funds(), "10.0000");
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn test_allow_dispute_when_locked() {
+        let mut processor = build_processor().with_policy(Policy {
+            allow_dispute_when_locked: true,
+            ..Default::default()
+        });
+        lock_account(&mut processor);
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 3, Some(1.0)))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 2, None))
+            .is_ok());
+
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "0.0000");
+        assert_eq!(account.held_funds(), "5.0000");
+        assert_eq!(processor.summary().ignored["AccountLocked"], 1);
+        assert_eq!(processor.summary().disputes, 2);
+    }
+
+    #[test]
+    fn test_unlock() {
+        // without allow_unlock the account stays locked
+        let mut processor = build_processor();
+        lock_account(&mut processor);
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::unlock, 1, 0, None))
+            .is_ok());
+        assert!(processor.store()[&AccountKey::from(1)].locked());
+        assert_eq!(processor.summary().ignored["UnlockNotAllowed"], 1);
+
+        let mut processor = build_processor().with_policy(Policy {
+            allow_unlock: true,
+            ..Default::default()
+        });
+        lock_account(&mut processor);
+        for operation in [
+            Operation::new(TypeOperation::unlock, 1, 0, Some(1.0)),
+            Operation::new(TypeOperation::unlock, 1, 0, None),
+            Operation::new(TypeOperation::deposit, 1, 3, Some(1.0)),
+            // an account which does not exist cannot be unlocked
+            Operation::new(TypeOperation::unlock, 2, 0, None),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+
+        // it accepts the deposits again, the charged back funds are not restored
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert!(!account.locked());
+        assert_eq!(account.available_funds(), "6.0000");
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::ChargedBack
+        );
+        // and the unlock did not take a tx id
+        assert_eq!(account.transaction_history.len(), 3);
+        assert_eq!(processor.summary().unlocks, 1);
+        assert_eq!(processor.summary().ignored["UnexpectedAmount"], 1);
+        assert_eq!(processor.summary().ignored["NonExistingAccount"], 1);
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let operations = [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(100.0)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, Some(40.0)),
+            // more than what is still held
+            Operation::new(TypeOperation::chargeback, 1, 1, Some(60.5)),
+            Operation::new(TypeOperation::resolve, 1, 1, Some(0.0)),
+        ];
+        let mut processor = build_processor().with_policy(Policy {
+            partial_disputes: true,
+            ..Default::default()
+        });
+        for operation in operations.iter().cloned() {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "40.0000");
+        assert_eq!(account.held_funds(), "60.0000");
+        assert!(!account.locked());
+        assert!(account.transaction_history[&1].disputed());
+        assert_eq!(processor.summary().ignored["DisputeError"], 1);
+        assert_eq!(processor.summary().ignored["ZeroAmount"], 1);
+
+        // the rest of the held funds closes the dispute
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::chargeback, 1, 1, Some(60.0)))
+            .is_ok());
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "40.0000");
+        assert_eq!(account.held_funds(), "0.0000");
+        assert_eq!(account.total(), "40.0000");
+        assert!(account.locked());
+        assert_eq!(
+            account.transaction_history[&1].dispute_state,
+            DisputeState::ChargedBack
+        );
+        assert!(account.check_invariants());
+
+        // without the policy the amounts are rejected
+        let mut processor = build_processor();
+        for operation in operations.iter().cloned() {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.held_funds(), "100.0000");
+        assert_eq!(processor.summary().ignored["UnexpectedAmount"], 3);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let almost_max = 900_000_000_000_000.0;
+        let mut processor = build_processor();
+        assert!(processor
+            .process_data(Operation::new(
+                TypeOperation::deposit,
+                1,
+                1,
+                Some(almost_max)
+            ))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(
+                TypeOperation::deposit,
+                1,
+                2,
+                Some(almost_max)
+            ))
+            .is_ok());
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "900000000000000.0000"
+        );
+        assert_eq!(processor.summary().ignored["Overflow"], 1);
+
+        let mut processor = build_processor().with_policy(Policy {
+            strict: true,
+            ..Default::default()
+        });
+        assert!(processor
+            .process_data(Operation::new(
+                TypeOperation::deposit,
+                1,
+                1,
+                Some(almost_max)
+            ))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(
+                TypeOperation::deposit,
+                1,
+                2,
+                Some(almost_max)
+            ))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let path = std::env::temp_dir().join(format!("atm_snapshot_{}.json", std::process::id()));
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, _receiver_results) = channel::channel::<writer::Command>(None);
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.5)),
+            Operation::new(TypeOperation::deposit, 2, 2, Some(3.0)),
+            Operation::new(TypeOperation::dispute, 2, 2, None),
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(0.25)),
+        ] {
+            assert!(sender_operations
+                .send(reader::Command::Data(operation))
+                .await
+                .is_ok());
+        }
+        assert!(sender_operations
+            .send(reader::Command::CloseConnection)
+            .await
+            .is_ok());
+        let mut processor =
+            Processor::build(receiver_operations, sender_results).with_snapshot(Some(path.clone()));
+        assert!(processor.run().await.is_ok());
+
+        // the accounts in the default currency are keyed by client
+        let saved: HashMap<u16, Account> =
+            serde_json::from_reader(std::fs::File::open(&path).expect("snapshot not saved"))
+                .expect("invalid snapshot");
+        assert_eq!(
+            snapshot::load(&path).expect("invalid snapshot"),
+            *processor.store()
+        );
+        std::fs::remove_file(&path).expect("cannot remove the snapshot");
+        assert_eq!(saved[&1].available_funds(), "10.2500");
+        assert!(saved[&2].transaction_history[&2].disputed());
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("atm_resume_{}.json", std::process::id()));
+        let mut previous = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+        ] {
+            assert!(previous.process_data(operation).is_ok());
+        }
+        assert!(snapshot::save(&path, previous.store().values()).is_ok());
+
+        let database = snapshot::load(&path).expect("cannot load the snapshot");
+        std::fs::remove_file(&path).expect("cannot remove the snapshot");
+        let mut processor = build_processor().with_database(database);
+        for operation in [
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            // the tx ids of the previous run are taken
+            Operation::new(TypeOperation::deposit, 2, 2, Some(1.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "5.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].held_funds(),
+            "10.0000"
+        );
+        assert!(!processor.store().contains_key(&AccountKey::from(2)));
+        assert_eq!(processor.summary().disputes, 1);
+        assert_eq!(processor.summary().ignored["RepeatedTransaction"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_after_close() {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let other_sender = sender_operations.clone();
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        // everything is queued before the processor starts, the close is not the last command
+        for command in [
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0))),
+            reader::Command::CloseConnection,
+        ] {
+            assert!(sender_operations.send(command).await.is_ok());
+        }
+        for command in [
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 2, Some(5.0))),
+            reader::Command::Data(Operation::new(TypeOperation::withdrawal, 1, 3, Some(2.5))),
+            reader::Command::CloseConnection,
+        ] {
+            assert!(other_sender.send(command).await.is_ok());
+        }
+        let mut processor = Processor::build(receiver_operations, sender_results);
+        assert!(processor.run().await.is_ok());
+        assert_eq!(processor.summary().applied(), 3);
+
+        match receiver_results.recv().await {
+            Some(writer::Command::Data(account)) => {
+                assert_eq!(account.available_funds(), "12.5000")
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+        assert!(matches!(
+            receiver_results.recv().await,
+            Some(writer::Command::CloseConnection)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        let (request_shutdown, shutdown) = watch::channel(false);
+        let mut processor =
+            Processor::build(receiver_operations, sender_results).with_shutdown(shutdown);
+        let handle = tokio::spawn(async move { processor.run().await });
+
+        for command in [
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0))),
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 2, 2, Some(1.0))),
+            reader::Command::Query(1),
+        ] {
+            assert!(sender_operations.send(command).await.is_ok());
+        }
+        // once the query is answered the operations before it were applied
+        assert!(matches!(
+            receiver_results.recv().await,
+            Some(writer::Command::Answer(_))
+        ));
+        assert!(request_shutdown.send(true).is_ok());
+        assert!(matches!(handle.await, Ok(Ok(()))));
+        // the input was not closed, nothing else is applied after the shutdown
+        assert!(sender_operations
+            .send(reader::Command::Data(Operation::new(
+                TypeOperation::deposit,
+                1,
+                3,
+                Some(1.0)
+            )))
+            .await
+            .is_err());
+
+        let mut balances = vec![];
+        let mut closed = false;
+        while let Some(command) = receiver_results.recv().await {
+            match command {
+                writer::Command::Data(account) => {
+                    balances.push((account.client_id(), account.available_funds()))
+                }
+                writer::Command::Answer(account) => panic!("unexpected answer {:?}", account),
+                writer::Command::CloseConnection => closed = true,
+            }
+        }
+        balances.sort();
+        assert_eq!(
+            balances,
+            vec![(1, "10.0000".to_string()), (2, "1.0000".to_string())]
+        );
+        assert!(closed);
+    }
+
+    #[tokio::test]
+    async fn test_writer_gone() {
+        for capacity in [None, Some(1)] {
+            let (sender_operations, receiver_operations) =
+                channel::channel::<reader::Command>(capacity);
+            let (sender_results, receiver_results) = channel::channel::<writer::Command>(capacity);
+            // e.g. the writer failed before the processor was done
+            drop(receiver_results);
+            let mut processor = Processor::build(receiver_operations, sender_results);
+            let handle = tokio::spawn(async move { processor.run().await });
+            for command in [
+                reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0))),
+                reader::Command::Data(Operation::new(TypeOperation::deposit, 2, 2, Some(1.0))),
+                reader::Command::CloseConnection,
+            ] {
+                assert!(sender_operations.send(command).await.is_ok());
+            }
+            // the work was done, nothing is lost but the reports
+            assert!(matches!(handle.await, Ok(Ok(()))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        for command in [
+            reader::Command::Query(1),
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 1, Some(10.0))),
+            reader::Command::Data(Operation::new(TypeOperation::withdrawal, 1, 2, Some(2.5))),
+            reader::Command::Query(1),
+            reader::Command::Data(Operation::new(TypeOperation::deposit, 1, 3, Some(1.0))),
+            reader::Command::CloseConnection,
+        ] {
+            assert!(sender_operations.send(command).await.is_ok());
+        }
+        let mut processor = Processor::build(receiver_operations, sender_results);
+        assert!(processor.run().await.is_ok());
+
+        // the client did not exist at the first query, then the running and the final balances
+        let mut balances = vec![];
+        while let Some(command) = receiver_results.recv().await {
+            match command {
+                writer::Command::Answer(account) => {
+                    balances.push(format!("answer {}", account.available_funds()))
+                }
+                writer::Command::Data(account) => balances.push(account.available_funds()),
+                writer::Command::CloseConnection => break,
+            }
+        }
+        assert_eq!(balances, vec!["answer 7.5000", "8.5000"]);
+    }
+
+    #[test]
+    fn test_zero_amount() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(0.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(0.0)),
+            Operation::new(TypeOperation::deposit, 2, 4, Some(1.0)),
+            transfer(1, 2, 5, 0.0),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["ZeroAmount"], 3);
+        assert_eq!(processor.summary().applied(), 2);
+        // nothing is recorded and the tx ids are still free
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)]
+                .transaction_history
+                .len(),
+            1
+        );
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 2, Some(1.0)))
+            .is_ok());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::withdrawal, 1, 3, Some(1.0)))
+            .is_ok());
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "10.0000"
+        );
+        assert_eq!(processor.summary().applied(), 4);
+    }
+
+    #[test]
+    fn test_withdrawal_reusing_deposit() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 1, Some(4.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().ignored["RepeatedTransaction"], 1);
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "10.0000");
+        // the deposit is untouched, so it can still be disputed in full
+        assert_eq!(
+            account.transaction_history[&1].type_transaction,
+            TransactionType::Deposit
+        );
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 1, None))
+            .is_ok());
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].held_funds(),
+            "10.0000"
+        );
+    }
+
+    #[test]
+    fn test_repeated_transaction() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(1.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(1.0)),
+            // the tx of a withdrawal cannot be reused by a deposit and the other way around
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 1, Some(5.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(processor.summary().applied(), 2);
+        assert_eq!(processor.summary().ignored["RepeatedTransaction"], 4);
+        assert_eq!(processor.summary().total_ignored(), 4);
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "9.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)]
+                .transaction_history
+                .len(),
+            2
+        );
+        assert_eq!(processor.store().len(), 1);
+    }
+
+    #[test]
+    fn test_record_rejection() {
+        let mut recorder = Recorder::default();
+        let rejected = Operation::new(TypeOperation::deposit, 1, 1, Some(1.0));
+        for (status, count) in [
+            (OperationStatus::AccountLocked, 1),
+            (OperationStatus::RepeatedTransaction, 2),
+            (OperationStatus::EmptyAmount, 1),
+            (OperationStatus::UnexpectedAmount, 1),
+            (OperationStatus::ZeroAmount, 2),
+            (OperationStatus::NonExistingTx, 3),
+            (OperationStatus::NonExistingAccount, 1),
+            (OperationStatus::DisputeError, 2),
+            (OperationStatus::Overflow, 1),
+            (OperationStatus::InvalidTransfer, 1),
+            (OperationStatus::InsufficientFunds, 2),
+            (OperationStatus::InvalidAmount, 1),
+            (OperationStatus::LimitExceeded, 2),
+            (OperationStatus::NoHistory, 1),
+            (OperationStatus::Unknown, 4),
+        ] {
+            for _ in 0..count {
+                assert!(recorder
+                    .record_rejection(&rejected, &status, &Default::default())
+                    .is_ok());
+            }
+            assert_eq!(recorder.summary.ignored[status.reason()], count);
+        }
+        assert_eq!(recorder.summary.total_ignored(), 25);
+        assert_eq!(recorder.summary.applied(), 0);
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut processor = build_processor().with_policy(Policy {
+            strict: true,
+            ..Default::default()
+        });
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 1, 1, Some(1.0)))
+            .is_ok());
+        let error = processor
+            .process_data(Operation::new(TypeOperation::withdrawal, 1, 2, Some(5.0)))
+            .expect_err("rejected operation accepted");
+        assert!(error.to_string().contains("tx: 2"));
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::dispute, 1, 3, None))
+            .is_err());
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 2, 1, Some(1.0)))
+            .is_err());
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "1.0000"
+        );
+    }
+
+    // Counts the bytes allocated by each thread, to check what an operation copies
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_dispute_large_history() {
+        let mut account = Account::build(1);
+        for tx in 0..100_000 {
+            assert!(account.add_funds(1.0).is_ok());
+            account.transaction_history.insert(
+                tx,
+                Transaction {
+                    client_id: 1,
+                    amount: 1.0,
+                    type_transaction: TransactionType::Deposit,
+                    dispute_state: DisputeState::None,
+                    settled: Default::default(),
+                },
+            );
+        }
+        for type_operation in [
+            TypeOperation::dispute,
+            TypeOperation::resolve,
+            TypeOperation::dispute,
+            TypeOperation::chargeback,
+        ] {
+            let before = ALLOCATED.with(Cell::get);
+            let status = Engine::execute_operation(
+                &mut account,
+                Operation::new(type_operation, 1, 5, None),
+                &Policy::default(),
+            );
+            // a copy of the history would take megabytes
+            assert!(ALLOCATED.with(Cell::get) - before < 1024);
+            match status {
+                OperationStatus::UpdateTransaction(tx, transaction) => {
+                    account.transaction_history.insert(tx, transaction);
+                }
+                status => panic!("unexpected status {:?}", status),
+            }
+        }
+        assert!(account.locked());
+        assert_eq!(account.available_funds(), "99999.0000");
+    }
+
+    #[test]
+    fn test_no_history() {
+        let mut processor = build_processor().with_history(false);
+        let mut disputed = Operation::new(TypeOperation::deposit, 2, 6, Some(1.0));
+        disputed.disputed = true;
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(2.5)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+            disputed,
+            // the tx ids are still checked
+            Operation::new(TypeOperation::deposit, 1, 2, Some(1.0)),
+            // neither side of a transfer is kept
+            Operation::new(TypeOperation::deposit, 3, 8, Some(1.0)),
+            transfer(1, 3, 9, 2.0),
+            transfer(1, 3, 9, 2.0),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "5.5000");
+        assert!(account.transaction_history.is_empty());
+        assert!(!account.locked());
+        let account = &processor.store()[&AccountKey::from(3)];
+        assert_eq!(account.available_funds(), "3.0000");
+        assert!(account.transaction_history.is_empty());
+        assert_eq!(processor.summary().ignored["NoHistory"], 4);
+        assert_eq!(processor.summary().ignored["RepeatedTransaction"], 2);
+    }
+
+    // Memory and time of a pure balance computation, with and without history
+    #[test]
+    #[ignore]
+    fn bench_no_history() {
+        for history in [true, false] {
+            let mut engine = Engine::build().with_history(history);
+            let before = ALLOCATED.with(Cell::get);
+            let start = std::time::Instant::now();
+            for tx in 0..1_000_000 {
+                // every deposit followed by a withdrawal of the same client
+                let type_operation = match tx % 2 {
+                    0 => TypeOperation::deposit,
+                    _ => TypeOperation::withdrawal,
+                };
+                let client = (tx / 2 % 1000) as u16;
+                assert!(engine
+                    .apply(Operation::new(type_operation, client, tx, Some(1.0)))
+                    .applied());
+            }
+            eprintln!(
+                "history {}: {:?}, {} MB allocated",
+                history,
+                start.elapsed(),
+                (ALLOCATED.with(Cell::get) - before) / 1_000_000
+            );
+        }
+    }
+
+    #[test]
+    fn test_overdraft_limit() {
+        let mut processor = build_processor().with_policy(Policy {
+            overdraft_limit: 5.0,
+            ..Default::default()
+        });
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            // within the limit
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(14.0)),
+            // beyond the limit
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(1.5)),
+            Operation::new(TypeOperation::withdrawal, 1, 4, Some(1.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "-5.0000"
+        );
+        assert_eq!(processor.store()[&AccountKey::from(1)].total(), "-5.0000");
+        assert_eq!(processor.summary().withdrawals, 2);
+        assert_eq!(processor.summary().ignored["InsufficientFunds"], 1);
+
+        // no overdraft by default
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(14.0)),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "10.0000"
+        );
+        assert_eq!(processor.summary().ignored["InsufficientFunds"], 1);
+    }
+
+    fn transfer(client: u16, dest: u16, tx: u32, amount: f64) -> Operation {
+        Operation::new(TypeOperation::transfer, client, tx, Some(amount)).with_dest(dest)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 2, 2, Some(1.0)),
+            transfer(1, 2, 3, 4.5),
+            // to itself or to a non existing client
+            transfer(1, 1, 4, 1.0),
+            transfer(1, 3, 5, 1.0),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "5.5000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(2)].available_funds(),
+            "5.5000"
+        );
+        assert_eq!(processor.summary().transfers, 1);
+        assert_eq!(processor.summary().ignored["InvalidTransfer"], 1);
+        assert_eq!(processor.summary().ignored["NonExistingAccount"], 1);
+        for account in processor.store().values() {
+            assert_funds_conserved(account);
+        }
+    }
+
+    #[test]
+    fn test_transfer_rollback() {
+        // without decimals, so the credited account can be close enough to the maximum
+        let mut processor = build_processor().with_decimals(0);
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10_000.0)),
+            Operation::new(TypeOperation::deposit, 2, 2, Some(2f64.powi(63) - 1024.0)),
+            // insufficient funds
+            transfer(1, 2, 3, 20_000.0),
+            // the credit overflows, so the debit is rolled back
+            transfer(1, 2, 4, 5_000.0),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+        }
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "10000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(2)].available_funds(),
+            "9223372036854774784"
+        );
+        assert_eq!(processor.summary().transfers, 0);
+        assert_eq!(processor.summary().ignored["InsufficientFunds"], 1);
+        assert_eq!(processor.summary().ignored["Overflow"], 1);
+    }
+
+    #[test]
+    fn test_transfer_locked() {
+        let mut processor = build_processor();
+        lock_account(&mut processor);
+        assert!(processor
+            .process_data(Operation::new(TypeOperation::deposit, 2, 10, Some(10.0)))
+            .is_ok());
+        assert!(processor.process_data(transfer(1, 2, 11, 1.0)).is_ok());
+        assert!(processor.process_data(transfer(2, 1, 12, 1.0)).is_ok());
+        assert_eq!(
+            processor.store()[&AccountKey::from(1)].available_funds(),
+            "5.0000"
+        );
+        assert_eq!(
+            processor.store()[&AccountKey::from(2)].available_funds(),
+            "10.0000"
+        );
+        assert_eq!(processor.summary().ignored["AccountLocked"], 2);
+    }
+
+    #[test]
+    fn test_invariants() {
+        let mut processor = build_processor();
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(2.5)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 1, None),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::dispute, 1, 2, None),
+            Operation::new(TypeOperation::chargeback, 1, 1, None),
+            Operation::new(TypeOperation::resolve, 1, 2, None),
+        ] {
+            assert!(processor.process_data(operation).is_ok());
+            assert!(processor.store()[&AccountKey::from(1)].check_invariants());
+        }
+        // the resolve is rejected because the chargeback locked the account
+        let account = &processor.store()[&AccountKey::from(1)];
+        assert_eq!(account.available_funds(), "0.0000");
+        assert_eq!(account.held_funds(), "2.5000");
+        assert!(account.locked());
+    }
+
+    #[tokio::test]
+    async fn test_bounded_channel() {
+        let (list_operations, _) = prepare_complex_test();
+        let mut unbounded = run_processor(list_operations, None).await;
+        // a capacity of 1 forces the reader to wait for the processor on every operation
+        let (list_operations, _) = prepare_complex_test();
+        let mut bounded = run_processor(list_operations, Some(1)).await;
+
+        unbounded.sort_by_key(|account| account.client_id);
+        bounded.sort_by_key(|account| account.client_id);
+        assert!(!bounded.is_empty());
+        assert_eq!(unbounded, bounded);
+    }
+
+    fn prepare_simple_test() -> (Vec<Operation>, HashMap<u16, Account>) {
+        let list_operations = vec![
+            Operation::new(TypeOperation::deposit, 1, 1, None),
+            Operation::new(TypeOperation::deposit, 3, 0, Some(2.000100)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(0.000100)),
+            Operation::new(TypeOperation::withdrawal, 1, 202, Some(0.000100)),
+            Operation::new(TypeOperation::deposit, 3, 1, Some(1.000100)),
+            Operation::new(TypeOperation::deposit, 3, 1, Some(1.000100)),
+            Operation::new(TypeOperation::deposit, 5, 4, Some(5.000100)),
+            Operation::new(TypeOperation::withdrawal, 10, 15, Some(5.000100)),
+            Operation::new(TypeOperation::withdrawal, 5, 105, Some(5.000200)),
+            Operation::new(TypeOperation::withdrawal, 5, 105, Some(5.00000)),
         ];
 
         let expected_results: HashMap<u16, Account> = HashMap::from([
@@ -239,16 +2280,21 @@ mod test {
                 1,
                 Account {
                     client_id: 1,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 0,
                     locked: false,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             2,
                             Transaction {
                                 client_id: 1,
                                 amount: 0.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -257,25 +2303,34 @@ mod test {
                                 client_id: 1,
                                 amount: 0.0001,
                                 type_transaction: TransactionType::Withdrawal,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
             (
                 3,
                 Account {
                     client_id: 3,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 30002,
                     locked: false,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             0,
                             Transaction {
                                 client_id: 3,
                                 amount: 2.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -284,25 +2339,34 @@ mod test {
                                 client_id: 3,
                                 amount: 1.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
             (
                 5,
                 Account {
                     client_id: 5,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 1,
                     locked: false,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             4,
                             Transaction {
                                 client_id: 5,
                                 amount: 5.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -311,9 +2375,13 @@ mod test {
                                 client_id: 5,
                                 amount: 5.0000,
                                 type_transaction: TransactionType::Withdrawal,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
         ]);
@@ -323,162 +2391,32 @@ mod test {
 
     fn prepare_complex_test() -> (Vec<Operation>, HashMap<u16, Account>) {
         let list_operations = vec![
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 0,
-                amount: Some(502.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 2,
-                amount: Some(320.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 1,
-                tx: 2,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 2,
-                tx: 0,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 1,
-                tx: 3,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 1,
-                tx: 2,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::resolve,
-                client: 1,
-                tx: 3,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 200,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::resolve,
-                client: 1,
-                tx: 2,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::resolve,
-                client: 1,
-                tx: 2,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 1,
-                tx: 201,
-                amount: Some(0.000100),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 2,
-                tx: 300,
-                amount: Some(1000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 2,
-                tx: 300,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 2,
-                tx: 301,
-                amount: Some(1000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::chargeback,
-                client: 2,
-                tx: 300,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 2,
-                tx: 301,
-                amount: Some(1000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 5,
-                tx: 500,
-                amount: Some(100_000_000_000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 5,
-                tx: 500,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 5,
-                tx: 501,
-                amount: Some(100_000_000_000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::chargeback,
-                client: 4,
-                tx: 500,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::resolve,
-                client: 5,
-                tx: 500,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::deposit,
-                client: 10,
-                tx: 600,
-                amount: Some(1000.0),
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 10,
-                tx: 600,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::resolve,
-                client: 10,
-                tx: 600,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::dispute,
-                client: 10,
-                tx: 600,
-                amount: None,
-            },
-            Operation {
-                type_operation: TypeOperation::chargeback,
-                client: 10,
-                tx: 600,
-                amount: None,
-            },
+            Operation::new(TypeOperation::deposit, 1, 0, Some(502.000100)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(320.000100)),
+            Operation::new(TypeOperation::dispute, 1, 2, Some(0.000100)),
+            Operation::new(TypeOperation::dispute, 2, 0, None),
+            Operation::new(TypeOperation::dispute, 1, 3, None),
+            Operation::new(TypeOperation::dispute, 1, 2, None),
+            Operation::new(TypeOperation::resolve, 1, 3, Some(0.000100)),
+            Operation::new(TypeOperation::deposit, 1, 200, Some(0.000100)),
+            Operation::new(TypeOperation::resolve, 1, 2, Some(0.000100)),
+            Operation::new(TypeOperation::resolve, 1, 2, None),
+            Operation::new(TypeOperation::deposit, 1, 201, Some(0.000100)),
+            Operation::new(TypeOperation::deposit, 2, 300, Some(1000.0)),
+            Operation::new(TypeOperation::dispute, 2, 300, None),
+            Operation::new(TypeOperation::deposit, 2, 301, Some(1000.0)),
+            Operation::new(TypeOperation::chargeback, 2, 300, None),
+            Operation::new(TypeOperation::deposit, 2, 301, Some(1000.0)),
+            Operation::new(TypeOperation::deposit, 5, 500, Some(500_000_000_000_000.0)),
+            Operation::new(TypeOperation::dispute, 5, 500, None),
+            Operation::new(TypeOperation::deposit, 5, 501, Some(500_000_000_000_000.0)),
+            Operation::new(TypeOperation::chargeback, 4, 500, None),
+            Operation::new(TypeOperation::resolve, 5, 500, None),
+            Operation::new(TypeOperation::deposit, 10, 600, Some(1000.0)),
+            Operation::new(TypeOperation::dispute, 10, 600, None),
+            Operation::new(TypeOperation::resolve, 10, 600, None),
+            Operation::new(TypeOperation::dispute, 10, 600, None),
+            Operation::new(TypeOperation::chargeback, 10, 600, None),
         ];
 
         let expected_results: HashMap<u16, Account> = HashMap::from([
@@ -486,16 +2424,21 @@ mod test {
                 1,
                 Account {
                     client_id: 1,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 8220004,
                     locked: false,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             0,
                             Transaction {
                                 client_id: 1,
                                 amount: 502.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -504,6 +2447,8 @@ mod test {
                                 client_id: 1,
                                 amount: 320.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::Resolved,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -512,6 +2457,8 @@ mod test {
                                 client_id: 1,
                                 amount: 0.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -520,25 +2467,34 @@ mod test {
                                 client_id: 1,
                                 amount: 0.0001,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
             (
                 2,
                 Account {
                     client_id: 2,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 10000000,
                     locked: true,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             300,
                             Transaction {
                                 client_id: 2,
                                 amount: 1000.0,
-                                type_transaction: TransactionType::ChargedBack,
+                                type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::ChargedBack,
+                                settled: Default::default(),
                             },
                         ),
                         (
@@ -547,55 +2503,76 @@ mod test {
                                 client_id: 2,
                                 amount: 1000.0,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
             (
+                // this is not a double count: tx 500 is still disputed, so its amount is held, while
+                // tx 501 sits in available. The resolve of tx 500 is rejected because releasing the
+                // held funds would overflow the available funds
                 5,
                 Account {
                     client_id: 5,
-                    held_funds: 1_000_000_000_000_000,
-                    available_funds: 1_000_000_000_000_000,
+                    currency: None,
+                    held_funds: 5_000_000_000_000_000_000,
+                    available_funds: 5_000_000_000_000_000_000,
                     locked: false,
-                    transaction_history: HashMap::from([
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([
                         (
                             500,
                             Transaction {
                                 client_id: 5,
-                                amount: 100_000_000_000.0,
-                                type_transaction: TransactionType::Dispute,
+                                amount: 500_000_000_000_000.0,
+                                type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::Disputed,
+                                settled: Default::default(),
                             },
                         ),
                         (
                             501,
                             Transaction {
                                 client_id: 5,
-                                amount: 100_000_000_000.0,
+                                amount: 500_000_000_000_000.0,
                                 type_transaction: TransactionType::Deposit,
+                                dispute_state: DisputeState::None,
+                                settled: Default::default(),
                             },
                         ),
                     ]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
             (
                 10,
                 Account {
                     client_id: 10,
+                    currency: None,
                     held_funds: 0,
                     available_funds: 0,
                     locked: true,
-                    transaction_history: HashMap::from([
-                        (
-                            600,
-                            Transaction {
-                                client_id: 10,
-                                amount: 1000.0,
-                                type_transaction: TransactionType::ChargedBack,
-                            },
-                        ),
-                    ]),
+                    decimals: 4,
+                    rounding: Default::default(),
+                    transaction_history: BTreeMap::from([(
+                        600,
+                        Transaction {
+                            client_id: 10,
+                            amount: 1000.0,
+                            type_transaction: TransactionType::Deposit,
+                            dispute_state: DisputeState::ChargedBack,
+                            settled: Default::default(),
+                        },
+                    )]),
+                    references: Default::default(),
+                    withdrawn: 0,
                 },
             ),
         ]);
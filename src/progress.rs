@@ -0,0 +1,126 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// Counters shared by the tasks to tell how far a long run is. A line is written to stderr at
+// most once per second, and the clock is only looked at every CHECK_EVERY counts so the
+// report does not slow the run down
+#[derive(Debug)]
+pub struct Progress {
+    start: Instant,
+    rows: AtomicU64,
+    applied: AtomicU64,
+    // milliseconds since the start of the last report
+    last_report: AtomicU64,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            rows: Default::default(),
+            applied: Default::default(),
+            last_report: Default::default(),
+        }
+    }
+}
+
+impl Progress {
+    const CHECK_EVERY: u64 = 1024;
+    const INTERVAL_MS: u64 = 1000;
+
+    // A row parsed and sent to the processor
+    pub fn record_row(&self) {
+        let rows = self.rows.fetch_add(1, Ordering::Relaxed) + 1;
+        if rows.is_multiple_of(Self::CHECK_EVERY) {
+            self.report_if_due();
+        }
+    }
+
+    // An operation applied to an account
+    pub fn record_applied(&self) {
+        let applied = self.applied.fetch_add(1, Ordering::Relaxed) + 1;
+        if applied.is_multiple_of(Self::CHECK_EVERY) {
+            self.report_if_due();
+        }
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows.load(Ordering::Relaxed)
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied.load(Ordering::Relaxed)
+    }
+
+    // Only one of the tasks writes the line if both get here at the same time
+    fn report_if_due(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        let last_report = self.last_report.load(Ordering::Relaxed);
+        if elapsed.saturating_sub(last_report) >= Self::INTERVAL_MS
+            && self
+                .last_report
+                .compare_exchange(last_report, elapsed, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            eprintln!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "progress: {} rows read, {} operations applied, {:.1}s elapsed",
+            self.rows(),
+            self.applied(),
+            self.start.elapsed().as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::channel;
+    use crate::processor::Processor;
+    use crate::progress::Progress;
+    use crate::reader::{Reader, Source};
+    use crate::{reader, writer};
+    use std::io;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_counters() {
+        // a withdrawal without funds every 10 rows, and one malformed row
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 1..=2500 {
+            if tx % 10 == 0 {
+                data.push_str(&format!("withdrawal,{},{},1000.0\n", tx % 7, tx));
+            } else {
+                data.push_str(&format!("deposit,{},{},1.0\n", tx % 7, tx));
+            }
+        }
+        data.push_str("deposit,1,x,1.0\n");
+
+        let progress = Arc::new(Progress::default());
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, _receiver_results) = channel::channel::<writer::Command>(None);
+        let reader = Reader::build(
+            sender_operations,
+            Source::Stream(Box::new(io::Cursor::new(data.into_bytes()))),
+            Default::default(),
+        )
+        .with_progress(progress.clone());
+        let mut processor =
+            Processor::build(receiver_operations, sender_results).with_progress(progress.clone());
+        assert_eq!(reader.run().await.ok(), Some(1));
+        assert!(processor.run().await.is_ok());
+
+        assert_eq!(progress.rows(), 2500);
+        assert_eq!(progress.applied(), 2250);
+        assert!(progress
+            .to_string()
+            .starts_with("progress: 2500 rows read, 2250 operations applied, "));
+    }
+}
@@ -1,33 +1,396 @@
+use crate::channel::Sender;
 use crate::parser;
-use crate::parser::Operation;
-use anyhow::Result;
-use tokio::sync::mpsc::UnboundedSender;
+use crate::parser::{InputFormat, Operation, ParseOptions};
+use crate::processor::shutdown_requested;
+use crate::progress::Progress;
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
 
 #[derive(Debug)]
 pub enum Command {
     Data(Operation),
+    // report the current account of a client right away, without waiting for the close
+    Query(u16),
     CloseConnection,
 }
 
+// Where the operations are read from
+pub enum Source {
+    // CSV file, parsed with the options of the reader
+    File(PathBuf),
+    // CSV data from any reader (e.g. an in-memory string), it is never decompressed
+    Stream(Box<dyn io::Read + Send>),
+    // operations already in memory (e.g. for testing)
+    Operations(Vec<Operation>),
+}
+
+// Implemented by hand because a stream is not Debug
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+            Self::Operations(operations) => f.debug_tuple("Operations").field(operations).finish(),
+        }
+    }
+}
+
 pub struct Reader {
-    sender: UnboundedSender<Command>,
+    sender: Sender<Command>,
+    source: Source,
+    options: ParseOptions,
+    progress: Option<Arc<Progress>>,
+    // how often a watched file is read again, it is only read once if None
+    watch: Option<Duration>,
+    shutdown: Option<watch::Receiver<bool>>,
 }
 
 impl Reader {
-    pub fn build(sender: UnboundedSender<Command>) -> Self {
-        Self { sender }
+    pub fn build(sender: Sender<Command>, source: Source, options: ParseOptions) -> Self {
+        Self {
+            sender,
+            source,
+            options,
+            progress: None,
+            watch: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
     }
 
-    // Parse the CSV and send the Operations through an unbounded channel to the processor task
-    pub async fn run(&self) -> Result<()> {
-        let operations = parser::parse()?;
+    // Instead of closing the connection at the end of the file, keep reading the rows appended
+    // to it every interval, until the shutdown. Only for a file source
+    pub fn with_watch(mut self, interval: Duration) -> Self {
+        self.watch = Some(interval);
+        self
+    }
+
+    // Once it is requested a watched file is not read anymore and the connection is closed
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Read the Operations from the source and send them through a channel to the processor task
+    // It returns the number of rows which could not be parsed
+    pub async fn run(mut self) -> Result<u64> {
+        let source = std::mem::replace(&mut self.source, Source::Operations(vec![]));
+        let (operations, skipped) = match source {
+            Source::File(input_file) => match self.watch {
+                Some(interval) => return self.tail(&input_file, interval).await,
+                None => parser::parse(&input_file, &self.options)?,
+            },
+            Source::Stream(stream) => parser::parse_rows(stream, &self.options)?,
+            Source::Operations(operations) => (operations, 0),
+        };
+        self.send_operations(operations).await?;
+        self.close().await?;
+        Ok(skipped)
+    }
+
+    // &mut since the source is not Sync, the futures of the reader would not be Send otherwise
+    async fn send_operations(&mut self, operations: Vec<Operation>) -> Result<()> {
         for operation in operations {
-            self.sender.send(Command::Data(operation))?;
+            let (client, tx) = (operation.client, operation.tx);
+            self.sender
+                .send(Command::Data(operation))
+                .await
+                .with_context(|| {
+                    format!(
+                        "Cannot send the operation of client {} and tx {} to the processor",
+                        client, tx
+                    )
+                })?;
+            if let Some(progress) = &self.progress {
+                progress.record_row();
+            }
             // There should be here a random time sleep to "emulate" a real operation
             // I didn't write it not to make slow the automated CLI tests
             // tokio::sleep(..).await;
         }
-        self.sender.send(Command::CloseConnection)?;
         Ok(())
     }
+
+    async fn close(&mut self) -> Result<()> {
+        self.sender
+            .send(Command::CloseConnection)
+            .await
+            .context("Cannot close the connection with the processor")
+    }
+
+    // Follow a file which is only appended to: the complete rows are parsed every interval (with
+    // the header of a CSV file) and followed by a query of every client they belong to, so their
+    // accounts are reported as soon as they are applied. A row still being written (without its
+    // line break) waits for the next read
+    async fn tail(&mut self, input_file: &Path, interval: Duration) -> Result<u64> {
+        if parser::is_gzip(input_file, &self.options) {
+            return Err(anyhow!(
+                "The compressed input file {} cannot be watched",
+                input_file.display()
+            ));
+        }
+        let mut file = tokio::fs::File::open(input_file)
+            .await
+            .with_context(|| format!("Cannot open the input file {}", input_file.display()))?;
+        let mut shutdown = self.shutdown.take();
+        let mut header: Option<Vec<u8>> = None;
+        // read but not parsed yet, everything after the last line break
+        let mut pending = vec![];
+        let mut skipped = 0;
+        loop {
+            file.read_to_end(&mut pending)
+                .await
+                .with_context(|| format!("Cannot read the input file {}", input_file.display()))?;
+            if let Some(end) = pending.iter().rposition(|byte| *byte == b'\n') {
+                let rest = pending.split_off(end + 1);
+                let rows = std::mem::replace(&mut pending, rest);
+                let rows = match &header {
+                    Some(header) => [header.as_slice(), &rows].concat(),
+                    // every line of a NDJSON input stands on its own
+                    None if self.options.format == InputFormat::Ndjson => rows,
+                    // the first line is the header of every later read
+                    None => {
+                        let end = rows.iter().position(|byte| *byte == b'\n').unwrap_or(end);
+                        header = Some(rows[..=end].to_vec());
+                        rows
+                    }
+                };
+                let (operations, rows_skipped) = parser::parse_rows(rows.as_slice(), &self.options)
+                    .with_context(|| {
+                        format!("Cannot parse the input file {}", input_file.display())
+                    })?;
+                skipped += rows_skipped;
+                let clients: BTreeSet<u16> = operations
+                    .iter()
+                    .map(|operation| operation.client)
+                    .collect();
+                self.send_operations(operations).await?;
+                for client in clients {
+                    self.sender
+                        .send(Command::Query(client))
+                        .await
+                        .context("Cannot send a query to the processor")?;
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown_requested(&mut shutdown) => break,
+            }
+        }
+        self.close().await?;
+        Ok(skipped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::channel;
+    use crate::parser::{Operation, ParseOptions, TypeOperation};
+    use crate::processor::Processor;
+    use crate::reader::{Command, Reader, Source};
+    use crate::writer::{self, OutputFormat, OutputMode, Writer};
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_commands() {
+        let operations = vec![
+            Operation::new(TypeOperation::deposit, 1, 1, Some(1.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 2, Some(0.5)),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+        ];
+        let (sender, mut receiver) = channel::channel::<Command>(None);
+        let reader = Reader::build(
+            sender,
+            Source::Operations(operations.clone()),
+            Default::default(),
+        );
+        assert_eq!(reader.run().await.ok(), Some(0));
+
+        // the sender is dropped with the reader, so the channel ends after the commands
+        let mut commands = vec![];
+        while let Some(command) = receiver.recv().await {
+            commands.push(command);
+        }
+        assert_eq!(commands.len(), operations.len() + 1);
+        for (command, expected) in commands.iter().zip(&operations) {
+            assert!(matches!(command, Command::Data(operation) if operation == expected));
+        }
+        assert!(matches!(commands.last(), Some(Command::CloseConnection)));
+    }
+
+    #[tokio::test]
+    async fn test_processor_gone() {
+        let (sender, receiver) = channel::channel::<Command>(None);
+        drop(receiver);
+        let reader = Reader::build(
+            sender,
+            Source::Operations(vec![Operation::new(
+                TypeOperation::deposit,
+                1,
+                7,
+                Some(1.0),
+            )]),
+            Default::default(),
+        );
+        let error = reader
+            .run()
+            .await
+            .expect_err("nobody receives the operations");
+        assert_eq!(
+            error.to_string(),
+            "Cannot send the operation of client 1 and tx 7 to the processor"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_rows() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,x,1.0
+deposit,1,2,1.0
+deposit,1,3,1.0
+deposit,1,4,1.0";
+        let (sender, mut receiver) = channel::channel::<Command>(None);
+        let reader = Reader::build(
+            sender,
+            Source::Stream(Box::new(io::Cursor::new(data.as_bytes().to_vec()))),
+            ParseOptions {
+                max_rows: Some(3),
+                ..Default::default()
+            },
+        );
+        // the malformed row is skipped but not counted
+        assert_eq!(reader.run().await.ok(), Some(1));
+
+        let mut commands = vec![];
+        while let Some(command) = receiver.recv().await {
+            commands.push(command);
+        }
+        assert_eq!(commands.len(), 4);
+        for (command, tx) in commands.iter().zip([1, 2, 3]) {
+            assert!(matches!(command, Command::Data(operation) if operation.tx == tx));
+        }
+        assert!(matches!(commands.last(), Some(Command::CloseConnection)));
+    }
+
+    // The next command, as text
+    async fn next(receiver: &mut channel::Receiver<Command>) -> String {
+        match receiver.recv().await {
+            Some(Command::Data(operation)) => format!("data {}", operation.tx),
+            Some(Command::Query(client)) => format!("query {}", client),
+            Some(Command::CloseConnection) => "close".to_string(),
+            None => "none".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let path = env::temp_dir().join(format!("atm_watch_{}.csv", std::process::id()));
+        fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").expect("cannot write");
+        let (sender, mut receiver) = channel::channel::<Command>(None);
+        let (request_shutdown, shutdown) = watch::channel(false);
+        let reader = Reader::build(sender, Source::File(path.clone()), Default::default())
+            .with_watch(Duration::from_millis(10))
+            .with_shutdown(shutdown);
+        let handle = tokio::spawn(reader.run());
+        assert_eq!(next(&mut receiver).await, "data 1");
+        assert_eq!(next(&mut receiver).await, "query 1");
+
+        // the last row is still being written, it is only read once it is complete
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("cannot open");
+        write!(file, "deposit,2,2,2.0\ndeposit,x,3,1.0\ndeposit,3,4,").expect("cannot append");
+        assert_eq!(next(&mut receiver).await, "data 2");
+        assert_eq!(next(&mut receiver).await, "query 2");
+        writeln!(file, "3.0").expect("cannot append");
+        assert_eq!(next(&mut receiver).await, "data 4");
+        assert_eq!(next(&mut receiver).await, "query 3");
+
+        // the connection is only closed on shutdown
+        assert!(request_shutdown.send(true).is_ok());
+        assert_eq!(next(&mut receiver).await, "close");
+        assert_eq!(handle.await.expect("reader panicked").ok(), Some(1));
+        fs::remove_file(&path).expect("cannot remove the input");
+    }
+
+    // The accounts of every batch are answered while watching, but only their final reports
+    // add up to the aggregate
+    #[tokio::test]
+    async fn test_watch_aggregate() {
+        let path = env::temp_dir().join(format!("atm_watch_aggregate_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n",
+        )
+        .expect("cannot write");
+        let (sender_operations, receiver_operations) = channel::channel::<Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        let (sender_writer, receiver_writer) = channel::channel::<writer::Command>(None);
+        let (request_shutdown, shutdown) = watch::channel(false);
+        let reader = Reader::build(
+            sender_operations,
+            Source::File(path.clone()),
+            Default::default(),
+        )
+        .with_watch(Duration::from_millis(10))
+        .with_shutdown(shutdown);
+        let start_reader = tokio::spawn(reader.run());
+        let mut processor = Processor::build(receiver_operations, sender_results);
+        let start_processor = tokio::spawn(async move { processor.run().await });
+        let mut writer = Writer::build(receiver_writer, OutputFormat::Csv, vec![])
+            .with_mode(OutputMode::Aggregate);
+        let start_writer = tokio::spawn(async move {
+            writer.run().await?;
+            Ok::<_, anyhow::Error>(writer.into_inner())
+        });
+
+        // a batch is appended once the previous one is answered (both clients, then one each)
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("cannot open");
+        let mut answers = 0;
+        while let Some(command) = receiver_results.recv().await {
+            if let writer::Command::Answer(_) = &command {
+                answers += 1;
+                match answers {
+                    2 => writeln!(file, "deposit,1,3,1.0").expect("cannot append"),
+                    3 => writeln!(file, "deposit,2,4,0.5").expect("cannot append"),
+                    4 => assert!(request_shutdown.send(true).is_ok()),
+                    _ => {}
+                }
+            }
+            assert!(sender_writer.send(command).await.is_ok());
+        }
+        drop(sender_writer);
+        assert_eq!(answers, 4);
+        assert_eq!(start_reader.await.expect("reader panicked").ok(), Some(0));
+        assert!(start_processor.await.expect("processor panicked").is_ok());
+        let out = start_writer
+            .await
+            .expect("writer panicked")
+            .expect("writer failed");
+        fs::remove_file(&path).expect("cannot remove the input");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "available,held,total,locked\n4.5000,0.0000,4.5000,0\n"
+        );
+    }
 }
@@ -0,0 +1,103 @@
+use crate::csv_log::CsvLog;
+use crate::parser::Operation;
+use crate::processor::OperationStatus;
+use anyhow::Result;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+const HEADER: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "dest",
+    "timestamp",
+    "reason",
+];
+
+// Original fields of a rejected operation, plus why it was rejected
+#[derive(Serialize)]
+struct RejectOutput {
+    #[serde(rename = "type")]
+    type_operation: String,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    dest: Option<u16>,
+    timestamp: Option<u64>,
+    reason: &'static str,
+}
+
+// CSV the rejected operations are written to, for reconciliation. It is shared by all the
+// processors, see CsvLog
+#[derive(Clone)]
+pub struct Rejects {
+    log: CsvLog,
+}
+
+impl Rejects {
+    // The header is written right away, so the file has it even if nothing is rejected
+    pub fn build<W: io::Write + Send + 'static>(destination: W) -> Result<Self> {
+        Ok(Self {
+            log: CsvLog::build(destination, &HEADER, "rejects file")?,
+        })
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            log: CsvLog::create(path, &HEADER, "rejects file")?,
+        })
+    }
+
+    pub fn record(&self, operation: &Operation, status: &OperationStatus) -> Result<()> {
+        let row = RejectOutput {
+            type_operation: format!("{:?}", operation.type_operation),
+            client: operation.client,
+            tx: operation.tx,
+            amount: operation.amount,
+            dest: operation.dest,
+            timestamp: operation.timestamp,
+            reason: status.reason(),
+        };
+        self.log.record(row)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.log.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::csv_log::test::{run_with_log, take, temp_path};
+    use crate::rejects::Rejects;
+
+    #[tokio::test]
+    async fn test_rejects() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,20.0
+deposit,1,3,1.5
+deposit,2,1,1.0";
+        let (skipped, written) =
+            run_with_log("rejects", data, Rejects::create, |processor, rejects| {
+                processor.with_rejects(rejects)
+            })
+            .await;
+        assert_eq!(skipped, 0);
+        assert_eq!(
+            written,
+            "type,client,tx,amount,dest,timestamp,reason
+withdrawal,1,2,20.0,,,InsufficientFunds
+deposit,2,1,1.0,,,RepeatedTransaction
+"
+        );
+
+        // only the header if nothing is rejected
+        let path = temp_path("rejects");
+        let rejects = Rejects::create(&path).expect("cannot create the rejects file");
+        assert!(rejects.flush().is_ok());
+        assert_eq!(take(&path), "type,client,tx,amount,dest,timestamp,reason\n");
+    }
+}
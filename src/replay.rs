@@ -0,0 +1,137 @@
+use crate::csv_log::CsvLog;
+use crate::parser::Operation;
+use anyhow::Result;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+const HEADER: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "dest",
+    "timestamp",
+    "currency",
+    "ref",
+    "disputed",
+];
+
+// An applied operation in the columns the parser reads by default
+#[derive(Serialize)]
+struct ReplayOutput<'a> {
+    #[serde(rename = "type")]
+    type_operation: String,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    dest: Option<u16>,
+    timestamp: Option<u64>,
+    currency: Option<&'a str>,
+    #[serde(rename = "ref")]
+    reference: Option<&'a str>,
+    // only written for the deposits which arrived disputed
+    disputed: Option<bool>,
+}
+
+// CSV of the operations which were applied, in the order they were applied. Run again (with
+// the same options) it leads to the same accounts, without the rejected rows of the input.
+// Like the rejects file it is shared by all the processors, see CsvLog
+#[derive(Clone)]
+pub struct Replay {
+    log: CsvLog,
+}
+
+impl Replay {
+    pub fn build<W: io::Write + Send + 'static>(destination: W) -> Result<Self> {
+        Ok(Self {
+            log: CsvLog::build(destination, &HEADER, "replay log")?,
+        })
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            log: CsvLog::create(path, &HEADER, "replay log")?,
+        })
+    }
+
+    // The type is written lowercase, whatever its casing in the input
+    pub fn record(&self, operation: &Operation) -> Result<()> {
+        let row = ReplayOutput {
+            type_operation: format!("{:?}", operation.type_operation),
+            client: operation.client,
+            tx: operation.tx,
+            amount: operation.amount,
+            dest: operation.dest,
+            timestamp: operation.timestamp,
+            currency: operation.currency.as_deref(),
+            reference: operation.reference.as_deref(),
+            disputed: operation.disputed.then_some(true),
+        };
+        self.log.record(row)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.log.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::csv_log::test::run_with_log;
+    use crate::pipeline::run_pipeline;
+    use crate::replay::Replay;
+    use std::io;
+
+    // The accounts are reported in no particular order
+    async fn balances(input: &str) -> Vec<String> {
+        let mut output = vec![];
+        run_pipeline(io::Cursor::new(input.as_bytes().to_vec()), &mut output)
+            .await
+            .expect("the pipeline failed");
+        let mut lines: Vec<String> = String::from_utf8(output)
+            .expect("the output is not UTF-8")
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let data = "type,client,tx,amount
+Deposit,1,1,10.0
+withdrawal,1,2,20.0
+DEPOSIT,2,3,1.25
+deposit,2,3,1.0
+withdrawal,1,4,2.5
+dispute,2,3,
+dispute,1,9,
+resolve,2,3,
+deposit,1,x,1.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,5,1.0";
+        let (skipped, written) =
+            run_with_log("replay", data, Replay::create, |processor, replay| {
+                processor.with_replay(replay)
+            })
+            .await;
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            written,
+            "type,client,tx,amount,dest,timestamp,currency,ref,disputed
+deposit,1,1,10.0,,,,,
+deposit,2,3,1.25,,,,,
+withdrawal,1,4,2.5,,,,,
+dispute,2,3,,,,,,
+resolve,2,3,,,,,,
+dispute,1,1,,,,,,
+chargeback,1,1,,,,,,
+"
+        );
+        // the log leads to the same accounts as the input
+        assert_eq!(balances(&written).await, balances(data).await);
+    }
+}
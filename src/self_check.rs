@@ -0,0 +1,274 @@
+use crate::account::{Account, AccountKey};
+use crate::parser::{Operation, TypeOperation};
+use crate::processor::OperationStatus;
+use crate::store::AccountStore;
+use std::collections::HashMap;
+use std::fmt;
+
+// Ledger kept apart from the accounts: what every account should have, only from the amounts
+// of the applied operations. Checked against the accounts at the end, it catches the funds
+// created or lost by a bug of the executors
+#[derive(Debug, Default)]
+pub struct SelfCheck {
+    // expected available + held funds of every account, in units
+    totals: HashMap<AccountKey, i128>,
+    // units still held by every disputed transaction
+    held: HashMap<(AccountKey, u32), i128>,
+}
+
+impl SelfCheck {
+    // The accounts the run starts from (e.g. loaded from a snapshot) are taken as they are
+    pub fn open<S: AccountStore>(accounts: &S) -> Self {
+        let mut self_check = Self::default();
+        for account in accounts.iter() {
+            let key = account.key();
+            self_check.totals.insert(key.clone(), total(account));
+            for (tx, transaction) in &account.transaction_history {
+                if transaction.disputed() {
+                    let held = account.held_by(transaction) as i128;
+                    self_check.held.insert((key.clone(), *tx), held);
+                }
+            }
+        }
+        self_check
+    }
+
+    // Called once the operation is applied. The amounts are scaled by its account, the same
+    // way the executors did
+    pub fn record<S: AccountStore>(
+        &mut self,
+        accounts: &S,
+        operation: &Operation,
+        status: &OperationStatus,
+    ) {
+        let key = AccountKey::build(operation.client, operation.currency.clone());
+        let account = match accounts.get(&key) {
+            Some(account) => account,
+            None => return,
+        };
+        let units = |amount: f64| account.scale(amount).unwrap_or_default() as i128;
+        let amount = operation.amount.map(units);
+        // the tx a dispute targets, which may come from its reference
+        let (tx, transaction) = match status {
+            OperationStatus::UpdateTransaction(tx, transaction) => (*tx, Some(transaction)),
+            _ => (operation.tx, None),
+        };
+        match operation.type_operation {
+            TypeOperation::deposit => {
+                *self.total(&key) += amount.unwrap_or_default();
+                if operation.disputed {
+                    self.held.insert((key, tx), amount.unwrap_or_default());
+                }
+            }
+            TypeOperation::withdrawal => *self.total(&key) -= amount.unwrap_or_default(),
+            TypeOperation::transfer => {
+                let amount = amount.unwrap_or_default();
+                *self.total(&key) -= amount;
+                if let Some(dest) = operation.dest {
+                    *self.total(&AccountKey::build(dest, operation.currency.clone())) += amount;
+                }
+            }
+            TypeOperation::dispute => {
+                if let Some(transaction) = transaction {
+                    self.held.insert((key, tx), units(transaction.amount));
+                }
+            }
+            TypeOperation::resolve => {
+                self.settle(key, tx, amount);
+            }
+            TypeOperation::chargeback => {
+                let settled = self.settle(key.clone(), tx, amount);
+                *self.total(&key) -= settled;
+            }
+            TypeOperation::unlock => {}
+        }
+    }
+
+    fn total(&mut self, key: &AccountKey) -> &mut i128 {
+        self.totals.entry(key.clone()).or_default()
+    }
+
+    // The part of the dispute a resolve or chargeback settles, all of it without an amount
+    fn settle(&mut self, key: AccountKey, tx: u32, amount: Option<i128>) -> i128 {
+        let held = self.held.entry((key, tx)).or_default();
+        let settled = amount.unwrap_or(*held);
+        *held -= settled;
+        settled
+    }
+
+    // Every account must have the funds the operations add up to, and hold what its disputed
+    // transactions still hold
+    pub fn check<S: AccountStore>(&self, accounts: &S) -> Reconciliation {
+        let mut held: HashMap<&AccountKey, i128> = HashMap::new();
+        for ((key, _), units) in &self.held {
+            *held.entry(key).or_default() += units;
+        }
+        let mut violations = vec![];
+        let mut checked = 0;
+        for account in accounts.iter() {
+            let key = account.key();
+            checked += 1;
+            let expected = self.totals.get(&key).copied().unwrap_or_default();
+            if total(account) != expected {
+                violations.push(Violation::build(account, "total", expected, total(account)));
+            }
+            let expected = held.get(&key).copied().unwrap_or_default();
+            if account.held_funds as i128 != expected {
+                let actual = account.held_funds as i128;
+                violations.push(Violation::build(account, "held", expected, actual));
+            }
+        }
+        // an account the operations went to cannot disappear
+        for key in self.totals.keys() {
+            if !accounts.contains(key) {
+                violations.push(Violation::Missing(key.clone()));
+            }
+        }
+        violations.sort_by(|a, b| a.key().cmp(b.key()));
+        Reconciliation {
+            checked,
+            violations,
+        }
+    }
+}
+
+fn total(account: &Account) -> i128 {
+    account.available_funds as i128 + account.held_funds as i128
+}
+
+// What the self-check found wrong in an account
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    // funds (total or held) which are not what the operations add up to
+    Funds {
+        key: AccountKey,
+        funds: &'static str,
+        expected: String,
+        actual: String,
+    },
+    // an account the operations went to is not in the store anymore
+    Missing(AccountKey),
+}
+
+impl Violation {
+    fn build(account: &Account, funds: &'static str, expected: i128, actual: i128) -> Self {
+        Self::Funds {
+            key: account.key(),
+            funds,
+            expected: Account::format_units(expected, account.decimals),
+            actual: Account::format_units(actual, account.decimals),
+        }
+    }
+
+    fn key(&self) -> &AccountKey {
+        match self {
+            Self::Funds { key, .. } | Self::Missing(key) => key,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = self.key();
+        write!(f, "client {}", key.client_id)?;
+        if let Some(currency) = &key.currency {
+            write!(f, " ({})", currency)?;
+        }
+        match self {
+            Self::Funds {
+                funds,
+                expected,
+                actual,
+                ..
+            } => write!(
+                f,
+                ": {} funds {}, the operations add up to {}",
+                funds, actual, expected
+            ),
+            Self::Missing(_) => write!(f, ": the account is missing"),
+        }
+    }
+}
+
+// Result of the self-check of a run
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciliation {
+    // number of accounts checked
+    pub checked: u64,
+    pub violations: Vec<Violation>,
+}
+
+impl Reconciliation {
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "self-check: {} accounts checked", self.checked)?;
+        writeln!(f, "inconsistencies: {}", self.violations.len())?;
+        for violation in &self.violations {
+            writeln!(f, "  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::AccountKey;
+    use crate::engine::Engine;
+    use crate::parser::{Operation, TypeOperation};
+    use crate::self_check::SelfCheck;
+
+    #[test]
+    fn test_check() {
+        let mut engine = Engine::build();
+        let mut self_check = SelfCheck::open(engine.accounts());
+        for operation in [
+            Operation::new(TypeOperation::deposit, 1, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 2, 2, Some(5.0)),
+            Operation::new(TypeOperation::withdrawal, 2, 3, Some(1.5)),
+            Operation::new(TypeOperation::transfer, 1, 4, Some(2.0)).with_dest(2),
+            Operation::new(TypeOperation::dispute, 1, 1, None),
+            Operation::new(TypeOperation::deposit, 1, 5, Some(3.0)),
+            Operation::new(TypeOperation::dispute, 1, 5, None),
+            Operation::new(TypeOperation::resolve, 1, 5, None),
+            // rejected, it is not in the ledger either
+            Operation::new(TypeOperation::withdrawal, 2, 6, Some(100.0)),
+        ] {
+            let status = engine.apply(operation.clone());
+            if status.applied() {
+                self_check.record(engine.accounts(), &operation, &status);
+            }
+        }
+        let reconciliation = self_check.check(engine.accounts());
+        assert!(reconciliation.is_consistent(), "{}", reconciliation);
+        assert_eq!(reconciliation.checked, 2);
+
+        // the funds of the dispute lost while being released, and an account gone
+        let mut accounts = engine.accounts().clone();
+        let account = accounts.get_mut(&AccountKey::from(1)).unwrap();
+        account.held_funds -= 40_000;
+        accounts.remove(&AccountKey::from(2));
+        let reconciliation = self_check.check(&accounts);
+        assert!(!reconciliation.is_consistent());
+        assert_eq!(
+            reconciliation.to_string(),
+            "self-check: 1 accounts checked
+inconsistencies: 3
+  client 1: total funds 7.0000, the operations add up to 11.0000
+  client 1: held funds 6.0000, the operations add up to 10.0000
+  client 2: the account is missing
+"
+        );
+
+        // the accounts the run starts from are taken as they are, their held funds must still
+        // match their disputes
+        let reconciliation = SelfCheck::open(engine.accounts()).check(engine.accounts());
+        assert!(reconciliation.is_consistent());
+        let reconciliation = SelfCheck::open(&accounts).check(&accounts);
+        assert_eq!(reconciliation.violations.len(), 1);
+    }
+}
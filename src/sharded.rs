@@ -0,0 +1,421 @@
+use crate::account::{Account, AccountKey, Rounding};
+use crate::channel;
+use crate::channel::{Receiver, Sender};
+use crate::parser::{Operation, TypeOperation};
+use crate::processor::{
+    report, shutdown_requested, OperationStatus, Policy, Processor, ProcessorOptions, Recorder,
+};
+use crate::progress::Progress;
+use crate::rejects::Rejects;
+use crate::replay::Replay;
+use crate::snapshot;
+use crate::summary::Summary;
+use crate::{reader, writer};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Processor which fans the operations out to several worker processors, partitioned by
+// `client_id % workers`. Each client is owned by exactly one worker, so the operations of
+// an account are still applied in order
+pub struct ShardedProcessor {
+    receiver: Receiver<reader::Command>,
+    sender: Sender<writer::Command>,
+    workers: usize,
+    decimals: u32,
+    rounding: Rounding,
+    policy: Policy,
+    // applied by the workers, the tx ids checked here are all kept
+    history_retention: Option<usize>,
+    // whether the workers keep the transaction histories
+    history: bool,
+    channel_capacity: Option<usize>,
+    // tx: client of the first operation which created it. Every worker only knows its own
+    // transactions, so the uniqueness of the tx ids across clients is checked here
+    transactions: HashMap<u32, u16>,
+    // operations rejected here, every worker records its own with a fork of it. The progress
+    // and the rejects file are shared by all of them, the replay is only written by the workers
+    recorder: Recorder,
+    // the workers only own part of the accounts, so the snapshot is saved here
+    snapshot: Option<PathBuf>,
+    // accounts to start from, handed over to their workers
+    database: HashMap<AccountKey, Account>,
+    // the workers stop as well, so none of them goes through its queue first
+    shutdown: Option<watch::Receiver<bool>>,
+}
+
+impl ShardedProcessor {
+    pub fn build(
+        receiver: Receiver<reader::Command>,
+        sender: Sender<writer::Command>,
+        workers: usize,
+    ) -> Self {
+        Self {
+            receiver,
+            sender,
+            workers: workers.max(1),
+            decimals: Account::DEFAULT_DECIMALS,
+            rounding: Default::default(),
+            policy: Default::default(),
+            history_retention: None,
+            history: true,
+            channel_capacity: None,
+            transactions: Default::default(),
+            recorder: Default::default(),
+            snapshot: None,
+            database: Default::default(),
+            shutdown: None,
+        }
+    }
+
+    pub fn with_options(self, options: ProcessorOptions) -> Self {
+        let mut processor = self
+            .with_decimals(options.decimals)
+            .with_rounding(options.rounding)
+            .with_policy(options.policy)
+            .with_history(options.history)
+            .with_channel_capacity(options.channel_capacity)
+            .with_database(options.database)
+            .with_snapshot(options.snapshot);
+        if let Some(history_retention) = options.history_retention {
+            processor = processor.with_history_retention(history_retention);
+        }
+        processor.recorder = Recorder::build(options.progress, options.rejects, options.replay);
+        if let Some(shutdown) = options.shutdown {
+            processor = processor.with_shutdown(shutdown);
+        }
+        processor
+    }
+
+    pub fn with_decimals(mut self, decimals: u32) -> Self {
+        self.decimals = decimals;
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_history_retention(mut self, history_retention: usize) -> Self {
+        self.history_retention = Some(history_retention);
+        self
+    }
+
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    // Capacity of the channels to the workers, unbounded by default
+    pub fn with_channel_capacity(mut self, channel_capacity: Option<usize>) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    // Accounts to start from (e.g. loaded from a snapshot)
+    pub fn with_database(mut self, database: HashMap<AccountKey, Account>) -> Self {
+        for account in database.values() {
+            for (tx, transaction) in &account.transaction_history {
+                self.transactions.insert(*tx, transaction.client_id);
+            }
+        }
+        self.database = database;
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.recorder.progress = Some(progress);
+        self
+    }
+
+    pub fn with_rejects(mut self, rejects: Rejects) -> Self {
+        self.recorder.rejects = Some(rejects);
+        self
+    }
+
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.recorder.replay = Some(replay);
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Counters of the operations processed by all the workers
+    pub fn summary(&self) -> &Summary {
+        &self.recorder.summary
+    }
+
+    // A tx id created by a client cannot be reused by another one. Unlike the processor, the
+    // tx id is taken by the first operation even if it fails, this way the outcome does not
+    // depend on the timing of the workers
+    fn is_repeated(&mut self, operation: &Operation) -> bool {
+        if !operation.type_operation.creates_transaction() {
+            return false;
+        }
+        let client_id = *self
+            .transactions
+            .entry(operation.tx)
+            .or_insert(operation.client);
+        client_id != operation.client
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let (sender_results, mut receiver_results) =
+            channel::channel::<writer::Command>(self.channel_capacity);
+        let mut senders = Vec::with_capacity(self.workers);
+        let mut handles: Vec<JoinHandle<Result<Summary>>> = Vec::with_capacity(self.workers);
+        let mut databases = vec![HashMap::new(); self.workers];
+        // all the currencies of a client are owned by the same worker
+        for (key, account) in self.database.drain() {
+            databases[key.client_id as usize % self.workers].insert(key, account);
+        }
+        for database in databases {
+            let (sender, receiver) = channel::channel::<reader::Command>(self.channel_capacity);
+            let mut processor = Processor::build(receiver, sender_results.clone())
+                .with_decimals(self.decimals)
+                .with_rounding(self.rounding)
+                .with_policy(self.policy)
+                .with_history(self.history)
+                .with_database(database)
+                .with_query_sender(self.sender.clone())
+                .with_recorder(self.recorder.fork());
+            if let Some(history_retention) = self.history_retention {
+                processor = processor.with_history_retention(history_retention);
+            }
+            if let Some(shutdown) = &self.shutdown {
+                processor = processor.with_shutdown(shutdown.clone());
+            }
+            handles.push(tokio::spawn(async move {
+                processor.run().await?;
+                Ok(processor.summary().clone())
+            }));
+            senders.push(sender);
+        }
+        // only the workers must keep the results channel open
+        drop(sender_results);
+
+        // route every operation to the worker which owns the client
+        loop {
+            let command = tokio::select! {
+                biased;
+                _ = shutdown_requested(&mut self.shutdown) => break,
+                command = self.receiver.recv() => match command {
+                    Some(command) => command,
+                    None => break,
+                },
+            };
+            match command {
+                reader::Command::CloseConnection => break,
+                // the worker answers straight to the writer, its results are only merged at the end
+                reader::Command::Query(client_id) => {
+                    let worker = client_id as usize % self.workers;
+                    if senders[worker]
+                        .send(reader::Command::Query(client_id))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                reader::Command::Data(operation) => {
+                    let worker = operation.client as usize % self.workers;
+                    if self.is_repeated(&operation) {
+                        self.recorder.reject(
+                            &operation,
+                            &OperationStatus::RepeatedTransaction,
+                            &self.policy,
+                        )?;
+                        continue;
+                    }
+                    // a transfer must be applied atomically over both accounts, which is not
+                    // possible if they are owned by different workers
+                    if operation.type_operation == TypeOperation::transfer
+                        && operation
+                            .dest
+                            .is_some_and(|dest| dest as usize % self.workers != worker)
+                    {
+                        self.recorder.reject(
+                            &operation,
+                            &OperationStatus::InvalidTransfer,
+                            &self.policy,
+                        )?;
+                        continue;
+                    }
+                    // a worker only stops early if it aborted, its error is reported below
+                    if senders[worker]
+                        .send(reader::Command::Data(operation))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        for sender in &senders {
+            let _ = sender.send(reader::Command::CloseConnection).await;
+        }
+
+        // merge the accounts of all the workers, the writer only gets one CloseConnection
+        let mut database = HashMap::new();
+        let mut closed = 0;
+        while let Some(command) = receiver_results.recv().await {
+            match command {
+                writer::Command::CloseConnection => {
+                    closed += 1;
+                    if closed == self.workers {
+                        break;
+                    }
+                }
+                writer::Command::Data(account) => {
+                    database.insert(account.key(), account);
+                }
+                // the workers send them straight to the writer
+                writer::Command::Answer(_) => {}
+            }
+        }
+        // nothing is reported if any of the workers aborted
+        for handle in handles {
+            self.recorder.summary.merge(&handle.await??);
+        }
+        for key in self.recorder.rejected_accounts.drain() {
+            let account = Account::build_empty(&key, self.decimals, self.rounding);
+            database.entry(key).or_insert(account);
+        }
+        self.recorder.flush()?;
+        if let Some(path) = &self.snapshot {
+            snapshot::save(path, database.values())?;
+        }
+        report(&self.sender, database.into_values()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::Account;
+    use crate::channel;
+    use crate::parser::{Operation, TypeOperation};
+    use crate::sharded::ShardedProcessor;
+    use crate::{reader, writer};
+
+    // Mixed input over several clients, including a tx id reused by another client
+    fn prepare_operations() -> Vec<Operation> {
+        let mut list_operations = vec![];
+        for client in 0..10u16 {
+            let tx = client as u32 * 100;
+            for (type_operation, tx, amount) in [
+                (TypeOperation::deposit, tx, Some(100.0)),
+                (TypeOperation::deposit, tx + 1, Some(50.5)),
+                (TypeOperation::deposit, 1, Some(1.0)),
+                (TypeOperation::withdrawal, tx + 2, Some(20.25)),
+                (TypeOperation::withdrawal, tx + 3, Some(1000.0)),
+                (TypeOperation::dispute, tx, None),
+                (TypeOperation::resolve, tx, None),
+                (TypeOperation::dispute, tx + 1, None),
+                (TypeOperation::chargeback, tx + 1, None),
+            ] {
+                list_operations.push(Operation::new(type_operation, client, tx, amount));
+            }
+        }
+        list_operations
+    }
+
+    async fn run_sharded(workers: usize) -> Vec<Account> {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        for operation in prepare_operations() {
+            assert!(sender_operations
+                .send(reader::Command::Data(operation))
+                .await
+                .is_ok());
+        }
+        assert!(sender_operations
+            .send(reader::Command::CloseConnection)
+            .await
+            .is_ok());
+
+        let mut processor = ShardedProcessor::build(receiver_operations, sender_results, workers);
+        assert!(processor.run().await.is_ok());
+        assert_eq!(processor.summary().applied(), 10 * 7);
+        assert_eq!(processor.summary().total_ignored(), 10 * 2);
+
+        let mut results = vec![];
+        while let Some(writer::Command::Data(account)) = receiver_results.recv().await {
+            results.push(account);
+        }
+        results.sort_by_key(|account| account.client_id);
+        results
+    }
+
+    #[tokio::test]
+    async fn test_workers() {
+        let single = run_sharded(1).await;
+        assert_eq!(single.len(), 10);
+        assert_eq!(single, run_sharded(4).await);
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        for operation in prepare_operations() {
+            assert!(sender_operations
+                .send(reader::Command::Data(operation))
+                .await
+                .is_ok());
+        }
+        for command in [
+            reader::Command::Query(3),
+            reader::Command::Data(Operation::new(
+                TypeOperation::deposit,
+                12,
+                10_000,
+                Some(1.0),
+            )),
+            reader::Command::Query(12),
+            reader::Command::CloseConnection,
+        ] {
+            assert!(sender_operations.send(command).await.is_ok());
+        }
+        let mut processor = ShardedProcessor::build(receiver_operations, sender_results, 4);
+        assert!(processor.run().await.is_ok());
+
+        // the answers come before the final reports, in no particular order across workers
+        let mut answers = vec![];
+        for _ in 0..2 {
+            match receiver_results.recv().await {
+                Some(writer::Command::Answer(account)) => {
+                    answers.push((account.client_id(), account.available_funds()))
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+        }
+        answers.sort();
+        assert_eq!(
+            answers,
+            vec![(3, "79.7500".to_string()), (12, "1.0000".to_string())]
+        );
+        let mut reports = 0;
+        while let Some(writer::Command::Data(_)) = receiver_results.recv().await {
+            reports += 1;
+        }
+        assert_eq!(reports, 11);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::account::{Account, AccountKey};
+use anyhow::{Context, Result};
+use serde::Serializer;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+// The whole database (transaction histories included) is stored as JSON, so a later run
+// can carry on from where this one finished. It is a map of client_id: Account, the key is
+// client_id:currency for the accounts which are not in the default currency
+pub fn save<'a>(path: &Path, accounts: impl Iterator<Item = &'a Account>) -> Result<()> {
+    let file = fs::File::create(path)
+        .with_context(|| format!("Cannot create the snapshot {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    let mut serializer = serde_json::Serializer::new(&mut writer);
+    serializer.collect_map(accounts.map(|account| {
+        let key = match account.currency() {
+            Some(currency) => format!("{}:{}", account.client_id(), currency),
+            None => account.client_id().to_string(),
+        };
+        (key, account)
+    }))?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Accounts saved by a previous run. They are keyed by what they hold, the keys of the map
+// are only there to read the file
+pub fn load(path: &Path) -> Result<HashMap<AccountKey, Account>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Cannot open the snapshot {}", path.display()))?;
+    let database: HashMap<String, Account> = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Invalid snapshot {}", path.display()))?;
+    Ok(database
+        .into_values()
+        .map(|account| (account.key(), account))
+        .collect())
+}
@@ -0,0 +1,139 @@
+use crate::account::{Account, AccountKey};
+use std::collections::HashMap;
+
+// Where the processor keeps the accounts. The default one is a HashMap in memory, but it can be
+// anything else (e.g. a persistent or a remote store). The accounts are keyed by client and currency
+pub trait AccountStore {
+    fn get(&self, key: &AccountKey) -> Option<&Account>;
+    fn get_mut(&mut self, key: &AccountKey) -> Option<&mut Account>;
+    // The account, created by build if it does not exist
+    fn get_or_create(&mut self, key: &AccountKey, build: impl FnOnce() -> Account) -> &mut Account;
+    fn insert(&mut self, account: Account);
+    fn remove(&mut self, key: &AccountKey) -> Option<Account>;
+    // Every account, in no particular order. Send so the accounts can be reported while
+    // iterating them
+    fn iter(&self) -> Box<dyn Iterator<Item = &Account> + Send + '_>;
+
+    fn contains(&self, key: &AccountKey) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl AccountStore for HashMap<AccountKey, Account> {
+    fn get(&self, key: &AccountKey) -> Option<&Account> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &AccountKey) -> Option<&mut Account> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn get_or_create(&mut self, key: &AccountKey, build: impl FnOnce() -> Account) -> &mut Account {
+        self.entry(key.clone()).or_insert_with(build)
+    }
+
+    fn insert(&mut self, account: Account) {
+        HashMap::insert(self, account.key(), account);
+    }
+
+    fn remove(&mut self, key: &AccountKey) -> Option<Account> {
+        HashMap::remove(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Account> + Send + '_> {
+        Box::new(self.values())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::{Account, AccountKey};
+    use crate::channel;
+    use crate::parser::{Operation, TypeOperation};
+    use crate::processor::Processor;
+    use crate::store::AccountStore;
+    use crate::{reader, writer};
+    use std::collections::BTreeMap;
+
+    // Store which counts how many times the accounts are looked up
+    #[derive(Default)]
+    struct CountingStore {
+        accounts: BTreeMap<AccountKey, Account>,
+        accesses: usize,
+    }
+
+    impl AccountStore for CountingStore {
+        fn get(&self, key: &AccountKey) -> Option<&Account> {
+            self.accounts.get(key)
+        }
+
+        fn get_mut(&mut self, key: &AccountKey) -> Option<&mut Account> {
+            self.accesses += 1;
+            self.accounts.get_mut(key)
+        }
+
+        fn get_or_create(
+            &mut self,
+            key: &AccountKey,
+            build: impl FnOnce() -> Account,
+        ) -> &mut Account {
+            self.accesses += 1;
+            self.accounts.entry(key.clone()).or_insert_with(build)
+        }
+
+        fn insert(&mut self, account: Account) {
+            self.accounts.insert(account.key(), account);
+        }
+
+        fn remove(&mut self, key: &AccountKey) -> Option<Account> {
+            self.accesses += 1;
+            self.accounts.remove(key)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = &Account> + Send + '_> {
+            Box::new(self.accounts.values())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_store() {
+        let (sender_operations, receiver_operations) = channel::channel::<reader::Command>(None);
+        let (sender_results, mut receiver_results) = channel::channel::<writer::Command>(None);
+        for operation in [
+            Operation::new(TypeOperation::deposit, 2, 1, Some(10.0)),
+            Operation::new(TypeOperation::deposit, 1, 2, Some(5.0)),
+            Operation::new(TypeOperation::withdrawal, 1, 3, Some(1.0)),
+            Operation::new(TypeOperation::transfer, 2, 4, Some(2.5)).with_dest(1),
+            // rejected before touching the store
+            Operation::new(TypeOperation::dispute, 3, 1, None),
+        ] {
+            assert!(sender_operations
+                .send(reader::Command::Data(operation))
+                .await
+                .is_ok());
+        }
+        assert!(sender_operations
+            .send(reader::Command::CloseConnection)
+            .await
+            .is_ok());
+
+        let mut processor = Processor::build_with_store(
+            receiver_operations,
+            sender_results,
+            CountingStore::default(),
+        );
+        assert!(processor.run().await.is_ok());
+        // two lookups per operation (three for the transfer)
+        assert_eq!(processor.store().accesses, 9);
+
+        // the reports follow the order of the store
+        let mut reports = vec![];
+        while let Some(writer::Command::Data(account)) = receiver_results.recv().await {
+            reports.push((account.client_id(), account.available_funds()));
+        }
+        assert_eq!(
+            reports,
+            vec![(1, "6.5000".to_string()), (2, "7.5000".to_string())]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::parser::TypeOperation;
+use crate::processor::OperationStatus;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Counters of what happened with the operations of a run, so the input rows can be
+// reconciled against the applied operations
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub transfers: u64,
+    pub unlocks: u64,
+    // reason (OperationStatus variant): number of ignored operations
+    pub ignored: BTreeMap<&'static str, u64>,
+    // input rows which could not be parsed, so they never became operations
+    pub skipped: u64,
+}
+
+impl Summary {
+    pub fn record_applied(&mut self, type_operation: TypeOperation) {
+        let counter = match type_operation {
+            TypeOperation::deposit => &mut self.deposits,
+            TypeOperation::withdrawal => &mut self.withdrawals,
+            TypeOperation::dispute => &mut self.disputes,
+            TypeOperation::resolve => &mut self.resolves,
+            TypeOperation::chargeback => &mut self.chargebacks,
+            TypeOperation::transfer => &mut self.transfers,
+            TypeOperation::unlock => &mut self.unlocks,
+        };
+        *counter += 1;
+    }
+
+    pub fn record_ignored(&mut self, status: &OperationStatus) {
+        *self.ignored.entry(status.reason()).or_default() += 1;
+    }
+
+    // Add up the counters of another summary (e.g. from another worker)
+    pub fn merge(&mut self, other: &Summary) {
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+        self.transfers += other.transfers;
+        self.unlocks += other.unlocks;
+        for (reason, count) in &other.ignored {
+            *self.ignored.entry(reason).or_default() += count;
+        }
+        self.skipped += other.skipped;
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.deposits
+            + self.withdrawals
+            + self.disputes
+            + self.resolves
+            + self.chargebacks
+            + self.transfers
+            + self.unlocks
+    }
+
+    pub fn total_ignored(&self) -> u64 {
+        self.ignored.values().sum()
+    }
+
+    // Nothing was skipped nor ignored
+    pub fn is_clean(&self) -> bool {
+        self.skipped == 0 && self.total_ignored() == 0
+    }
+}
+
+// One line per category
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "deposits: {}", self.deposits)?;
+        writeln!(f, "withdrawals: {}", self.withdrawals)?;
+        writeln!(f, "disputes: {}", self.disputes)?;
+        writeln!(f, "resolves: {}", self.resolves)?;
+        writeln!(f, "chargebacks: {}", self.chargebacks)?;
+        writeln!(f, "transfers: {}", self.transfers)?;
+        // only with --allow-unlock, otherwise they are ignored
+        if self.unlocks > 0 {
+            writeln!(f, "unlocks: {}", self.unlocks)?;
+        }
+        for (reason, count) in &self.ignored {
+            writeln!(f, "ignored ({}): {}", reason, count)?;
+        }
+        if self.skipped > 0 {
+            writeln!(f, "skipped rows: {}", self.skipped)?;
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,63 @@
-#[derive(Debug, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+// What created the transaction, it never changes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
-    Dispute,
+    // funds sent to another client
+    TransferOut,
+    // funds received from another client
+    TransferIn,
+}
+
+// Where the transaction is in the dispute lifecycle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum DisputeState {
+    #[default]
+    None,
+    // its funds are held
+    Disputed,
+    // the dispute was resolved, it can be disputed again
+    Resolved,
+    // final: its funds were withdrawn and the account locked
     ChargedBack,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// Parts of the amount of a disputed transaction already released or withdrawn by partial
+// resolves and chargebacks, reset once the dispute is over
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settlement {
+    pub resolved: f64,
+    pub charged_back: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     pub client_id: u16,
     pub amount: f64,
     pub type_transaction: TransactionType,
+    pub dispute_state: DisputeState,
+    #[serde(default)]
+    pub settled: Settlement,
+}
+
+impl Transaction {
+    // Whether the transaction is currently under dispute (its funds are held)
+    pub fn disputed(&self) -> bool {
+        self.dispute_state == DisputeState::Disputed
+    }
+
+    // Whether it can no longer be disputed: only deposits can, until they are charged back
+    pub fn finalized(&self) -> bool {
+        self.type_transaction != TransactionType::Deposit
+            || self.dispute_state == DisputeState::ChargedBack
+    }
+
+    // Part of the amount still held while it is disputed
+    pub fn held_amount(&self) -> f64 {
+        self.amount - self.settled.resolved - self.settled.charged_back
+    }
 }
 
 // For testing purposes
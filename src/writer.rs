@@ -1,55 +1,869 @@
 use crate::account::Account;
-use anyhow::Result;
+use crate::channel::Receiver;
+use crate::transaction::{DisputeState, TransactionType};
+use anyhow::{anyhow, Context, Result};
 use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
 use std::io;
-use tokio::sync::mpsc::UnboundedReceiver;
 
+// The amounts are already formatted by the account from its signed units, this way they are
+// written with its exact precision and sign: the available funds and the total go negative
+// with an overdraft or the dispute of a spent deposit
 #[derive(Serialize)]
 pub struct Output {
     pub client: u16,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    // only written with the currency column, empty for the default currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
     pub locked: bool,
 }
 
+impl Output {
+    pub fn build(account: &Account, with_currency: bool) -> Self {
+        Self {
+            client: account.client_id(),
+            currency: currency_column(account, with_currency),
+            available: account.available_funds(),
+            held: account.held_funds(),
+            total: account.total(),
+            locked: account.locked(),
+        }
+    }
+
+    fn trim_zeros(self) -> Self {
+        Self {
+            available: Account::trim_zeros(&self.available).to_string(),
+            held: Account::trim_zeros(&self.held).to_string(),
+            total: Account::trim_zeros(&self.total).to_string(),
+            ..self
+        }
+    }
+}
+
+// One row per transaction of an account, for auditing
+#[derive(Serialize)]
+pub struct TransactionOutput {
+    pub client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub type_transaction: TransactionType,
+    pub amount: String,
+    pub dispute_state: DisputeState,
+}
+
+// Sums of all the accounts (of a currency), for a quick reconciliation
+#[derive(Serialize)]
+pub struct AggregateOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    // number of locked accounts
+    pub locked: u64,
+}
+
+impl AggregateOutput {
+    fn trim_zeros(self) -> Self {
+        Self {
+            available: Account::trim_zeros(&self.available).to_string(),
+            held: Account::trim_zeros(&self.held).to_string(),
+            total: Account::trim_zeros(&self.total).to_string(),
+            ..self
+        }
+    }
+}
+
+// Running sums of the aggregate mode, in stored units. A single account is within MAX_VALUE
+// but many of them can add up beyond it, so they are summed as i128 and checked anyway. The
+// accounts may have different decimals (e.g. from a snapshot), the sums are kept with the most
+// of them so none is truncated
+#[derive(Debug, Default)]
+struct Aggregate {
+    available: i128,
+    held: i128,
+    locked: u64,
+    decimals: u32,
+}
+
+impl Aggregate {
+    fn add(&mut self, account: &Account) -> Result<()> {
+        let overflow = || anyhow!("The aggregate of the accounts overflows");
+        let decimals = self.decimals.max(account.decimals);
+        let rescale = |units: i128, from: u32| {
+            10i128
+                .checked_pow(decimals - from)
+                .and_then(|scale| units.checked_mul(scale))
+                .ok_or_else(overflow)
+        };
+        let available = rescale(account.available_funds as i128, account.decimals)?;
+        let held = rescale(account.held_funds as i128, account.decimals)?;
+        self.available = rescale(self.available, self.decimals)?
+            .checked_add(available)
+            .ok_or_else(overflow)?;
+        self.held = rescale(self.held, self.decimals)?
+            .checked_add(held)
+            .ok_or_else(overflow)?;
+        self.decimals = decimals;
+        self.locked += account.locked() as u64;
+        Ok(())
+    }
+
+    fn output(&self, currency: Option<String>) -> Result<AggregateOutput> {
+        let total = self
+            .available
+            .checked_add(self.held)
+            .ok_or_else(|| anyhow!("The aggregate of the accounts overflows"))?;
+        Ok(AggregateOutput {
+            currency,
+            available: Account::format_units(self.available, self.decimals),
+            held: Account::format_units(self.held, self.decimals),
+            total: Account::format_units(total, self.decimals),
+            locked: self.locked,
+        })
+    }
+}
+
+// The aggregates of every currency
+#[derive(Debug, Default)]
+struct Aggregates(BTreeMap<Option<String>, Aggregate>);
+
+impl Aggregates {
+    fn add(&mut self, account: &Account) -> Result<()> {
+        self.0
+            .entry(account.currency.clone())
+            .or_default()
+            .add(account)
+    }
+
+    // There is always at least one row, all zeros without accounts
+    fn rows(&mut self, with_currency: bool, trim_zeros: bool) -> Result<Vec<Row>> {
+        if self.0.is_empty() {
+            self.0.insert(
+                None,
+                Aggregate {
+                    decimals: Account::DEFAULT_DECIMALS,
+                    ..Default::default()
+                },
+            );
+        }
+        let mut rows = vec![];
+        for (currency, aggregate) in std::mem::take(&mut self.0) {
+            let output = aggregate.output(with_currency.then(|| currency.unwrap_or_default()))?;
+            rows.push(Row::Aggregate(if trim_zeros {
+                output.trim_zeros()
+            } else {
+                output
+            }));
+        }
+        Ok(rows)
+    }
+}
+
+// All the accounts are selected without a set of clients
+fn is_selected(clients: &Option<HashSet<u16>>, account: &Account) -> bool {
+    clients
+        .as_ref()
+        .is_none_or(|clients| clients.contains(&account.client_id()))
+}
+
+fn currency_column(account: &Account, with_currency: bool) -> Option<String> {
+    with_currency.then(|| account.currency().unwrap_or_default().to_string())
+}
+
+// Fields of the rows which hold an amount
+const AMOUNT_FIELDS: &[&str] = &["available", "held", "total", "amount"];
+
+// The row as JSON, with its amounts as numbers. They keep the exact text the account formatted
+// them with (serde_json is built with arbitrary_precision), e.g. 10.2500 and not 10.25
+fn json_row(row: Row) -> Result<serde_json::Value> {
+    let mut value = serde_json::to_value(row).context("Cannot write the reports")?;
+    if let Some(fields) = value.as_object_mut() {
+        for field in AMOUNT_FIELDS {
+            if let Some(value) = fields.get_mut(*field) {
+                if let serde_json::Value::String(amount) = value {
+                    let number = amount
+                        .parse()
+                        .with_context(|| format!("Cannot write the amount {}", amount))?;
+                    *value = serde_json::Value::Number(number);
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+// Rows written for every account
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Row {
+    Account(Output),
+    Transaction(TransactionOutput),
+    Aggregate(AggregateOutput),
+}
+
+// What is written about the accounts
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputMode {
+    // one report per account
+    #[default]
+    Summary,
+    // the transaction history of every account, in ascending tx order
+    History,
+    // a single row with the sums of all the accounts (one per currency), written once the
+    // connection is closed
+    Aggregate,
+}
+
+impl OutputMode {
+    fn rows(&self, account: &Account, with_currency: bool, trim_zeros: bool) -> Result<Vec<Row>> {
+        match self {
+            Self::Summary => {
+                let output = Output::build(account, with_currency);
+                if trim_zeros {
+                    Ok(vec![Row::Account(output.trim_zeros())])
+                } else {
+                    Ok(vec![Row::Account(output)])
+                }
+            }
+            Self::History => {
+                // already sorted by tx
+                account
+                    .transaction_history
+                    .iter()
+                    .map(|(tx, transaction)| {
+                        // the units which were applied, the same as the balances
+                        let units = account.scale(transaction.amount).ok_or_else(|| {
+                            anyhow!(
+                                "Cannot write the amount of tx {} of client {}",
+                                tx,
+                                account.client_id()
+                            )
+                        })?;
+                        let amount = Account::format_units(units as i128, account.decimals);
+                        Ok(Row::Transaction(TransactionOutput {
+                            client: account.client_id(),
+                            currency: currency_column(account, with_currency),
+                            tx: *tx,
+                            type_transaction: transaction.type_transaction.clone(),
+                            amount: if trim_zeros {
+                                Account::trim_zeros(&amount).to_string()
+                            } else {
+                                amount
+                            },
+                            dispute_state: transaction.dispute_state,
+                        }))
+                    })
+                    .collect()
+            }
+            // the accounts are only summed, see Aggregates
+            Self::Aggregate => Ok(vec![]),
+        }
+    }
+}
+
+// Format the client reports are written with
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // a JSON array of the reports
+    Json,
+}
+
 #[derive(Debug)]
 pub enum Command {
     Data(Account),
+    // the account at some point of the run, answering a query. It is written right away but
+    // not added to the aggregate, the final report of the account comes later as Data
+    Answer(Account),
     CloseConnection,
 }
 
-pub struct Writer {
-    receiver: UnboundedReceiver<Command>,
+// The writer is generic over the destination (e.g. stdout, a file or an in-memory buffer)
+pub struct Writer<W: io::Write> {
+    receiver: Receiver<Command>,
+    format: OutputFormat,
+    mode: OutputMode,
+    // delimiter of the CSV format
+    delimiter: u8,
+    // flush every CSV record instead of buffering them until the connection is closed
+    flush_each: bool,
+    // add the currency of the accounts to the rows
+    with_currency: bool,
+    // write the amounts without the trailing zeros of their decimals
+    trim_zeros: bool,
+    // write the CSV header before the first row
+    headers: bool,
+    // sums of the accounts by currency, in the aggregate mode
+    aggregates: Aggregates,
+    // only the accounts of these clients are written, all of them if None
+    clients: Option<HashSet<u16>>,
+    destination: W,
 }
 
-impl Writer {
-    pub fn build(receiver: UnboundedReceiver<Command>) -> Self {
-        Self { receiver }
+impl<W: io::Write> Writer<W> {
+    pub fn build(receiver: Receiver<Command>, format: OutputFormat, destination: W) -> Self {
+        Self {
+            receiver,
+            format,
+            mode: Default::default(),
+            delimiter: b',',
+            flush_each: false,
+            with_currency: false,
+            trim_zeros: false,
+            headers: true,
+            aggregates: Default::default(),
+            clients: None,
+            destination,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: OutputMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    // Lower latency for a live consumer, at the cost of throughput
+    pub fn with_flush_each(mut self, flush_each: bool) -> Self {
+        self.flush_each = flush_each;
+        self
+    }
+
+    pub fn with_currency(mut self, with_currency: bool) -> Self {
+        self.with_currency = with_currency;
+        self
+    }
+
+    pub fn with_trim_zeros(mut self, trim_zeros: bool) -> Self {
+        self.trim_zeros = trim_zeros;
+        self
+    }
+
+    // Without the header, e.g. when the rows are appended to the ones of a previous run
+    pub fn with_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
     }
 
-    // Receive the results through an unbounded channel and write them to the stdout output
+    // The accounts of the rest of clients are still processed (their transactions can be
+    // disputed), they are only left out of the output, the aggregate included
+    pub fn with_clients(mut self, clients: HashSet<u16>) -> Self {
+        self.clients = Some(clients);
+        self
+    }
+
+    // Receive the results through a channel and write them to the destination
     pub async fn run(&mut self) -> Result<()> {
-        let mut writer = csv::WriterBuilder::new().from_writer(io::stdout());
+        match self.format {
+            OutputFormat::Csv => self.write_csv().await,
+            OutputFormat::Json => self.write_json().await,
+        }
+    }
+
+    // Give back the destination, e.g. to inspect what was written
+    pub fn into_inner(self) -> W {
+        self.destination
+    }
+
+    async fn write_csv(&mut self) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.headers)
+            .from_writer(&mut self.destination);
+        while let Some(data) = self.receiver.recv().await {
+            match data {
+                Command::CloseConnection => {
+                    if self.mode == OutputMode::Aggregate {
+                        for row in self.aggregates.rows(self.with_currency, self.trim_zeros)? {
+                            writer
+                                .serialize(row)
+                                .context("Cannot write the aggregate of the accounts")?;
+                        }
+                    }
+                    writer.flush().context("Cannot flush the reports")?;
+                }
+                Command::Data(account) if !is_selected(&self.clients, &account) => {}
+                Command::Data(account) => {
+                    if self.mode == OutputMode::Aggregate {
+                        self.aggregates.add(&account)?;
+                    }
+                    for row in self
+                        .mode
+                        .rows(&account, self.with_currency, self.trim_zeros)?
+                    {
+                        writer.serialize(row).with_context(|| {
+                            format!("Cannot write the report of client {}", account.client_id())
+                        })?;
+                    }
+                    if self.flush_each {
+                        writer.flush().context("Cannot flush the reports")?;
+                    }
+                }
+                // only the sums are written in the aggregate mode
+                Command::Answer(_) if self.mode == OutputMode::Aggregate => {}
+                Command::Answer(account) if !is_selected(&self.clients, &account) => {}
+                // flushed as well, whoever asked is waiting for it
+                Command::Answer(account) => {
+                    for row in self
+                        .mode
+                        .rows(&account, self.with_currency, self.trim_zeros)?
+                    {
+                        writer.serialize(row).with_context(|| {
+                            format!("Cannot write the report of client {}", account.client_id())
+                        })?;
+                    }
+                    writer.flush().context("Cannot flush the reports")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A JSON array cannot be streamed record by record, so the reports are written all together
+    // once the connection is closed
+    async fn write_json(&mut self) -> Result<()> {
+        let mut reports = vec![];
         while let Some(data) = self.receiver.recv().await {
             match data {
                 Command::CloseConnection => {
-                    writer.flush()?;
+                    if self.mode == OutputMode::Aggregate {
+                        for row in self.aggregates.rows(self.with_currency, self.trim_zeros)? {
+                            reports.push(json_row(row)?);
+                        }
+                    }
+                    serde_json::to_writer(&mut self.destination, &reports)
+                        .context("Cannot write the reports")?;
+                    writeln!(self.destination).context("Cannot write the reports")?;
+                    self.destination
+                        .flush()
+                        .context("Cannot flush the reports")?;
+                    reports.clear();
                 }
+                Command::Data(account) if !is_selected(&self.clients, &account) => {}
                 Command::Data(account) => {
-                    let held_funds = account.held_funds().parse::<f64>()?;
-                    let available_funds = account.available_funds().parse::<f64>()?;
-                    let client_report = Output {
-                        client: account.client_id(),
-                        available: available_funds,
-                        held: held_funds,
-                        total: available_funds + held_funds,
-                        locked: account.locked(),
-                    };
-                    writer.serialize(client_report)?;
+                    if self.mode == OutputMode::Aggregate {
+                        self.aggregates.add(&account)?;
+                    }
+                    for row in self
+                        .mode
+                        .rows(&account, self.with_currency, self.trim_zeros)?
+                    {
+                        reports.push(json_row(row)?);
+                    }
                 }
+                // an array cannot be written in parts, --watch does not take JSON
+                Command::Answer(_) => {}
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::account::{Account, Rounding};
+    use crate::channel;
+    use crate::transaction::{DisputeState, Transaction, TransactionType};
+    use crate::writer::{Aggregate, Command, OutputFormat, OutputMode, Writer};
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    async fn write_all(commands: Vec<Command>, format: OutputFormat) -> Vec<u8> {
+        write_all_with_mode(commands, format, OutputMode::Summary).await
+    }
+
+    async fn write_all_with_mode(
+        commands: Vec<Command>,
+        format: OutputFormat,
+        mode: OutputMode,
+    ) -> Vec<u8> {
+        let (sender, receiver) = channel::channel::<Command>(None);
+        for command in commands {
+            assert!(sender.send(command).await.is_ok());
+        }
+        drop(sender);
+
+        let mut writer = Writer::build(receiver, format, vec![]).with_mode(mode);
+        assert!(writer.run().await.is_ok());
+        writer.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_csv_rows() {
+        let mut first = Account::build(1);
+        assert!(first.add_funds(1.5).is_ok());
+        assert!(first.block_funds(0.5).is_ok());
+        let mut second = Account::build(2);
+        assert!(second.add_funds(2.0001).is_ok());
+        let mut third = Account::build(3);
+        third.lock();
+        let out = write_all(
+            vec![
+                Command::Data(first),
+                Command::Data(second),
+                Command::Data(third),
+                Command::CloseConnection,
+            ],
+            OutputFormat::Csv,
+        )
+        .await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked
+1,1.0000,0.5000,1.5000,false
+2,2.0001,0.0000,2.0001,false
+3,0.0000,0.0000,0.0000,true
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let mut first = Account::build(1);
+        assert!(first.add_funds(10.5).is_ok());
+        assert!(first.block_funds(0.25).is_ok());
+        let mut second = Account::build(2);
+        second.lock();
+        let out = write_all(
+            vec![
+                Command::Data(first),
+                Command::Data(second),
+                Command::CloseConnection,
+            ],
+            OutputFormat::Json,
+        )
+        .await;
+
+        // the amounts are numbers, written with the precision of the accounts
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[{"client":1,"available":10.2500,"held":0.2500,"total":10.5000,"locked":false},{"client":2,"available":0.0000,"held":0.0000,"total":0.0000,"locked":true}]
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overdrawn() {
+        let overdrawn = || {
+            let mut account = Account::build(1);
+            assert!(account.add_funds(1.0).is_ok());
+            assert!(account.overdraw_funds(3.5, 5.0).is_ok());
+            account
+        };
+        // the dispute holds more than what is left
+        let disputed = || {
+            let mut account = Account::build(2);
+            assert!(account.add_funds(1.0).is_ok());
+            assert!(account.overdraw_funds(1.5, 5.0).is_ok());
+            assert!(account.hold_funds(1.0).is_ok());
+            account
+        };
+        let commands = || {
+            vec![
+                Command::Data(overdrawn()),
+                Command::Data(disputed()),
+                Command::CloseConnection,
+            ]
+        };
+        let out = write_all(commands(), OutputFormat::Csv).await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked
+1,-2.5000,0.0000,-2.5000,false
+2,-1.5000,1.0000,-0.5000,false
+"
+        );
+        let out = write_all(commands(), OutputFormat::Json).await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[{"client":1,"available":-2.5000,"held":0.0000,"total":-2.5000,"locked":false},{"client":2,"available":-1.5000,"held":1.0000,"total":-0.5000,"locked":false}]
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clients() {
+        let (sender, receiver) = channel::channel::<Command>(None);
+        for client in 1..=8 {
+            let mut account = Account::build(client);
+            assert!(account.add_funds(client as f64).is_ok());
+            assert!(sender.send(Command::Data(account)).await.is_ok());
+        }
+        assert!(sender.send(Command::CloseConnection).await.is_ok());
+        drop(sender);
+
+        let mut writer = Writer::build(receiver, OutputFormat::Csv, vec![])
+            .with_clients(HashSet::from([1, 7, 9]));
+        assert!(writer.run().await.is_ok());
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+7,7.0000,0.0000,7.0000,false
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trim_zeros() {
+        let mut account = Account::build(1);
+        assert!(account.add_funds(10.5001).is_ok());
+        assert!(account.block_funds(0.0001).is_ok());
+        let (sender, receiver) = channel::channel::<Command>(None);
+        assert!(sender.send(Command::Data(account)).await.is_ok());
+        assert!(sender.send(Command::CloseConnection).await.is_ok());
+        drop(sender);
+
+        let mut writer = Writer::build(receiver, OutputFormat::Csv, vec![]).with_trim_zeros(true);
+        assert!(writer.run().await.is_ok());
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "client,available,held,total,locked
+1,10.5,0.0001,10.5001,false
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aggregate() {
+        let mut first = Account::build(1);
+        assert!(first.add_funds(10.5).is_ok());
+        assert!(first.block_funds(0.25).is_ok());
+        let mut second = Account::build(2);
+        assert!(second.add_funds(2.0).is_ok());
+        assert!(second.block_funds(2.0).is_ok());
+        // a disputed deposit already withdrawn leaves the available funds negative
+        second.available_funds = -10_000;
+        second.lock();
+        let mut third = Account::build(3);
+        third.lock();
+        let commands = vec![
+            Command::Data(first),
+            Command::Data(second),
+            Command::Data(third),
+            Command::CloseConnection,
+        ];
+        let out = write_all_with_mode(commands, OutputFormat::Csv, OutputMode::Aggregate).await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "available,held,total,locked
+9.2500,2.2500,11.5000,2
+"
+        );
+
+        // without accounts everything is zero
+        let out = write_all_with_mode(
+            vec![Command::CloseConnection],
+            OutputFormat::Json,
+            OutputMode::Aggregate,
+        )
+        .await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[{"available":0.0000,"held":0.0000,"total":0.0000,"locked":0}]
+"#
+        );
+
+        // the accounts at MAX_VALUE add up beyond it
+        let mut account = Account::build(1);
+        account.available_funds = Account::MAX_VALUE as i64;
+        let mut aggregate = Aggregate::default();
+        for _ in 0..3 {
+            assert!(aggregate.add(&account).is_ok());
+        }
+        assert_eq!(
+            aggregate.output(None).unwrap().available,
+            Account::format_units(3 * Account::MAX_VALUE as i128, 4)
+        );
+        // and beyond the sums themselves it is an error, not a wrong figure
+        aggregate.available = i128::MAX - 1;
+        assert!(aggregate.add(&account).is_err());
+        aggregate.available = i128::MAX - 1;
+        aggregate.held = 2;
+        assert!(aggregate.output(None).is_err());
+
+        // the accounts with fewer decimals are scaled up, whichever comes first
+        let mut two_decimals = Account::build_with_decimals(1, 2);
+        assert!(two_decimals.add_funds(1.25).is_ok());
+        assert!(two_decimals.block_funds(0.5).is_ok());
+        let mut six_decimals = Account::build_with_decimals(2, 6);
+        assert!(six_decimals.add_funds(0.000001).is_ok());
+        for accounts in [
+            [&two_decimals, &six_decimals],
+            [&six_decimals, &two_decimals],
+        ] {
+            let mut aggregate = Aggregate::default();
+            for account in accounts {
+                assert!(aggregate.add(account).is_ok());
+            }
+            let output = aggregate.output(None).unwrap();
+            assert_eq!(output.available, "0.750001");
+            assert_eq!(output.held, "0.500000");
+            assert_eq!(output.total, "1.250001");
+        }
+        // and scaling them up can overflow as well
+        let mut aggregate = Aggregate {
+            available: i128::MAX / 10,
+            ..Default::default()
+        };
+        assert!(aggregate.add(&Account::build_with_decimals(2, 15)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let mut account = Account::build(1);
+        for (tx, amount, type_transaction, dispute_state) in [
+            (7, 2.5, TransactionType::Withdrawal, DisputeState::None),
+            (3, 10.0, TransactionType::Deposit, DisputeState::None),
+            (5, 1.25, TransactionType::Deposit, DisputeState::Disputed),
+        ] {
+            account.transaction_history.insert(
+                tx,
+                Transaction {
+                    client_id: 1,
+                    amount,
+                    type_transaction,
+                    dispute_state,
+                    settled: Default::default(),
+                },
+            );
+        }
+        let out = write_all_with_mode(
+            vec![Command::Data(account), Command::CloseConnection],
+            OutputFormat::Csv,
+            OutputMode::History,
+        )
+        .await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,tx,type,amount,dispute_state
+1,3,Deposit,10.0000,None
+1,5,Deposit,1.2500,Disputed
+1,7,Withdrawal,2.5000,None
+"
+        );
+    }
+
+    // The amounts of the history are the ones applied to the balances
+    #[tokio::test]
+    async fn test_history_rounding() {
+        let mut account = Account::build(1);
+        account.rounding = Rounding::Truncate;
+        for (tx, amount) in [(1, 1.00009), (2, 0.00015)] {
+            assert!(account.add_funds(amount).is_ok());
+            account.transaction_history.insert(
+                tx,
+                Transaction {
+                    client_id: 1,
+                    amount,
+                    type_transaction: TransactionType::Deposit,
+                    dispute_state: DisputeState::None,
+                    settled: Default::default(),
+                },
+            );
+        }
+        assert_eq!(account.available_funds(), "1.0001");
+        let out = write_all_with_mode(
+            vec![Command::Data(account), Command::CloseConnection],
+            OutputFormat::Csv,
+            OutputMode::History,
+        )
+        .await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,tx,type,amount,dispute_state
+1,1,Deposit,1.0000,None
+1,2,Deposit,0.0001,None
+"
+        );
+    }
+
+    // Destination which can be inspected while the writer is running
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_each() {
+        for flush_each in [false, true] {
+            let buffer = SharedBuffer::default();
+            let (sender, receiver) = channel::channel::<Command>(None);
+            let mut writer = Writer::build(receiver, OutputFormat::Csv, buffer.clone())
+                .with_flush_each(flush_each);
+            let start_writer = tokio::spawn(async move { writer.run().await });
+
+            assert!(sender.send(Command::Data(Account::build(1))).await.is_ok());
+            // give the writer the chance to handle the record
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            if flush_each {
+                assert_eq!(
+                    written,
+                    "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+                );
+            } else {
+                assert!(written.is_empty());
+            }
+
+            assert!(sender.send(Command::CloseConnection).await.is_ok());
+            drop(sender);
+            assert!(start_writer.await.expect("writer panicked").is_ok());
+            assert!(!buffer.0.lock().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let mut account = Account::build(1);
+        assert!(account.add_funds(1.0).is_ok());
+
+        // written right away, without flush_each
+        let buffer = SharedBuffer::default();
+        let (sender, receiver) = channel::channel::<Command>(None);
+        let mut writer = Writer::build(receiver, OutputFormat::Csv, buffer.clone());
+        let start_writer = tokio::spawn(async move { writer.run().await });
+        assert!(sender.send(Command::Answer(account.clone())).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        drop(sender);
+        assert!(start_writer.await.expect("writer panicked").is_ok());
+
+        // only the final report is summed
+        let commands = vec![
+            Command::Answer(account.clone()),
+            Command::Data(account),
+            Command::CloseConnection,
+        ];
+        let out = write_all_with_mode(commands, OutputFormat::Csv, OutputMode::Aggregate).await;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "available,held,total,locked\n1.0000,0.0000,1.0000,0\n"
+        );
+    }
+}